@@ -1,25 +1,314 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
 
 pub use envconfig::Envconfig;
+use rust_decimal::Decimal;
 use url::Url;
 
+use crate::{
+    exchanges::{self, reconnect::Backoff},
+    order_book::{Pair, ParsePairError},
+};
+
+/// Comma separated list of pairs, e.g. `eth/btc,ltc/btc`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Pairs(pub Vec<Pair>);
+impl FromStr for Pairs {
+    type Err = ParsePairError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(Pair::from_str)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Comma separated list of exchange names, e.g. `binance,coinbase`
+///
+/// Binance futures books are served as separate exchanges, `binance-usdm` and `binance-coinm`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Exchanges(pub Vec<String>);
+impl FromStr for Exchanges {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(|exchange| exchange.trim().to_lowercase())
+                .filter(|exchange| !exchange.is_empty())
+                .collect(),
+        ))
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Entry {0:?} must be formatted as `{1}`")]
+pub struct ParseEntryError(String, &'static str);
+
+/// Comma separated quotes substituted on exchanges, e.g. `bitstamp:usdt=usd` to serve usdt pairs
+/// by bitstamp books quoted in usd
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct QuoteSubstitutes(pub Vec<(String, String, String)>);
+impl QuoteSubstitutes {
+    /// Quote of the exchange books merged into the pair quoted in `quote_currency`
+    pub fn quote_currency<'q>(&'q self, exchange_name: &str, quote_currency: &'q str) -> &'q str {
+        self.0
+            .iter()
+            .find(|(exchange, served, _)| exchange == exchange_name && served == quote_currency)
+            .map_or(quote_currency, |(_, _, substitute)| substitute)
+    }
+}
+impl FromStr for QuoteSubstitutes {
+    type Err = ParseEntryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once(':')
+                    .and_then(|(exchange, quotes)| Some((exchange, quotes.split_once('=')?)))
+                    .filter(|(exchange, (served, substitute))| {
+                        !exchange.is_empty() && !served.is_empty() && !substitute.is_empty()
+                    })
+                    .map(|(exchange, (served, substitute))| {
+                        (
+                            exchange.trim().to_lowercase(),
+                            served.trim().to_lowercase(),
+                            substitute.trim().to_lowercase(),
+                        )
+                    })
+                    .ok_or_else(|| ParseEntryError(entry.to_owned(), "exchange:quote=quote"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Comma separated fixed rates, e.g. `usdt/usd=1.0001` for the price of one usdt in usd
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FxRates(pub Vec<(Pair, Decimal)>);
+impl FromStr for FxRates {
+    type Err = ParseEntryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once('=')
+                    .and_then(|(pair, rate)| {
+                        Some((
+                            Pair::from_str(pair).ok()?,
+                            Decimal::from_str(rate.trim()).ok()?,
+                        ))
+                    })
+                    .ok_or_else(|| ParseEntryError(entry.to_owned(), "base/quote=rate"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Comma separated pairs with exchanges whose books keep the rates live, e.g. `usdt/usd@kraken`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FxRateSources(pub Vec<(Pair, String)>);
+impl FromStr for FxRateSources {
+    type Err = ParseEntryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once('@')
+                    .filter(|(_, exchange)| !exchange.trim().is_empty())
+                    .and_then(|(pair, exchange)| {
+                        Some((Pair::from_str(pair).ok()?, exchange.trim().to_lowercase()))
+                    })
+                    .ok_or_else(|| ParseEntryError(entry.to_owned(), "base/quote@exchange"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Comma separated taker fees of exchanges, e.g. `binance=0.001,kraken=0.0026`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TakerFees(pub HashMap<String, Decimal>);
+impl FromStr for TakerFees {
+    type Err = ParseEntryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once('=')
+                    .and_then(|(exchange, fee)| {
+                        Some((
+                            exchange.trim().to_lowercase(),
+                            Decimal::from_str(fee.trim()).ok()?,
+                        ))
+                    })
+                    .filter(|(exchange, fee)| {
+                        !exchange.is_empty() && !fee.is_sign_negative() && *fee < Decimal::ONE
+                    })
+                    .ok_or_else(|| ParseEntryError(entry.to_owned(), "exchange=fee"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
 #[derive(Debug, Envconfig, PartialEq)]
 pub struct Config {
     #[envconfig(from = "ORDERBOOK_ADDR", default = "127.0.0.1:7777")]
     pub addr: SocketAddr,
+    /// Address of the service for adding and removing sources at runtime, not served if not set
+    #[envconfig(from = "ORDERBOOK_ADMIN_ADDR")]
+    pub admin_addr: Option<SocketAddr>,
     #[envconfig(
         from = "BINANCE_WEBSOCKET_ADDR",
         default = "wss://stream.binance.com:443/ws"
     )]
     pub binance_websocket_addr: Url,
+    #[envconfig(from = "BINANCE_REST_ADDR", default = "https://api.binance.com/")]
+    pub binance_rest_addr: Url,
+    /// Maintain the full depth binance book from diff events instead of partial book snapshots
+    #[envconfig(from = "BINANCE_DIFF_DEPTH", default = "false")]
+    pub binance_diff_depth: bool,
+    #[envconfig(from = "BINANCE_SNAPSHOT_LIMIT", default = "1000")]
+    pub binance_snapshot_limit: u16,
+    /// Share one websocket of the combined endpoint (`/stream` instead of `/ws`) by all pairs
+    /// of a binance market, the diff depth books still use a websocket per pair
+    #[envconfig(from = "BINANCE_COMBINED_STREAMS", default = "false")]
+    pub binance_combined_streams: bool,
+    #[envconfig(
+        from = "BINANCE_USDM_WEBSOCKET_ADDR",
+        default = "wss://fstream.binance.com/ws"
+    )]
+    pub binance_usdm_websocket_addr: Url,
+    #[envconfig(from = "BINANCE_USDM_REST_ADDR", default = "https://fapi.binance.com/")]
+    pub binance_usdm_rest_addr: Url,
+    #[envconfig(
+        from = "BINANCE_COINM_WEBSOCKET_ADDR",
+        default = "wss://dstream.binance.com/ws"
+    )]
+    pub binance_coinm_websocket_addr: Url,
+    #[envconfig(
+        from = "BINANCE_COINM_REST_ADDR",
+        default = "https://dapi.binance.com/"
+    )]
+    pub binance_coinm_rest_addr: Url,
+    #[envconfig(
+        from = "BITFINEX_WEBSOCKET_ADDR",
+        default = "wss://api-pub.bitfinex.com/ws/2"
+    )]
+    pub bitfinex_websocket_addr: Url,
     #[envconfig(from = "BITSTAMP_WEBSOCKET_ADDR", default = "wss://ws.bitstamp.net/")]
     pub bitstamp_websocket_addr: Url,
+    #[envconfig(from = "BITSTAMP_REST_ADDR", default = "https://www.bitstamp.net/")]
+    pub bitstamp_rest_addr: Url,
+    /// Maintain the full depth bitstamp book from `diff_order_book` instead of top 100 snapshots
+    #[envconfig(from = "BITSTAMP_DIFF_ORDER_BOOK", default = "false")]
+    pub bitstamp_diff_order_book: bool,
+    /// File caching bitstamp pairs, loaded when `trading-pairs-info` is not reachable at startup
+    #[envconfig(from = "BITSTAMP_PAIRS_CACHE_PATH")]
+    pub bitstamp_pairs_cache_path: Option<PathBuf>,
+    #[envconfig(from = "BITSTAMP_PAIRS_REFRESH_SECS", default = "3600")]
+    pub bitstamp_pairs_refresh_secs: u64,
+    #[envconfig(
+        from = "BYBIT_WEBSOCKET_ADDR",
+        default = "wss://stream.bybit.com/v5/public/spot"
+    )]
+    pub bybit_websocket_addr: Url,
+    /// Levels of the bybit `orderbook` topic, one of 1, 50 or 200
+    #[envconfig(from = "BYBIT_DEPTH", default = "50")]
+    pub bybit_depth: exchanges::bybit::Depth,
+    /// Bybit drops connections silent for a while, 20 seconds is the interval it recommends
+    #[envconfig(from = "BYBIT_PING_INTERVAL_SECS", default = "20")]
+    pub bybit_ping_interval_secs: u64,
+    #[envconfig(
+        from = "COINBASE_WEBSOCKET_ADDR",
+        default = "wss://ws-feed.exchange.coinbase.com"
+    )]
+    pub coinbase_websocket_addr: Url,
+    #[envconfig(
+        from = "GEMINI_WEBSOCKET_ADDR",
+        default = "wss://api.gemini.com/v2/marketdata"
+    )]
+    pub gemini_websocket_addr: Url,
+    #[envconfig(from = "HTX_WEBSOCKET_ADDR", default = "wss://api.huobi.pro/ws")]
+    pub htx_websocket_addr: Url,
+    #[envconfig(from = "KRAKEN_WEBSOCKET_ADDR", default = "wss://ws.kraken.com/v2")]
+    pub kraken_websocket_addr: Url,
+    #[envconfig(from = "KUCOIN_REST_ADDR", default = "https://api.kucoin.com/")]
+    pub kucoin_rest_addr: Url,
+    #[envconfig(
+        from = "OKX_WEBSOCKET_ADDR",
+        default = "wss://ws.okx.com:8443/ws/v5/public"
+    )]
+    pub okx_websocket_addr: Url,
+    /// Exchanges subscribed to for every served pair
+    #[envconfig(from = "EXCHANGES", default = "binance,bitstamp")]
+    pub exchanges: Exchanges,
     #[envconfig(from = "BASE_CURRENCY", default = "btc")]
     pub base_currency: String,
     #[envconfig(from = "QUOTE_CURRENCY", default = "usdt")]
     pub quote_currency: String,
+    /// Pairs served in addition to the one of `BASE_CURRENCY` and `QUOTE_CURRENCY`
+    #[envconfig(from = "ADDITIONAL_PAIRS", default = "")]
+    pub additional_pairs: Pairs,
+    /// Json file of asset aliases and instruments listed by exchanges, pairs of exchanges with
+    /// a listing are checked before subscribing
+    #[envconfig(from = "INSTRUMENTS_PATH")]
+    pub instruments_path: Option<PathBuf>,
+    /// Quotes of exchange books merged into pairs in other quotes, converted by fx rates
+    /// of `FX_RATES` and `FX_RATE_SOURCES`, none by default
+    #[envconfig(from = "QUOTE_SUBSTITUTES", default = "")]
+    pub quote_substitutes: QuoteSubstitutes,
+    /// Rates used until a live one is received from `FX_RATE_SOURCES`
+    #[envconfig(from = "FX_RATES", default = "")]
+    pub fx_rates: FxRates,
+    #[envconfig(from = "FX_RATE_SOURCES", default = "")]
+    pub fx_rate_sources: FxRateSources,
+    /// Fractions of taker fees, e.g. `0.001` for 0.1%
+    #[envconfig(from = "TAKER_FEES", default = "")]
+    pub taker_fees: TakerFees,
+    /// Rank and publish levels by prices with `TAKER_FEES`, raw prices are sent along
+    #[envconfig(from = "FEE_ADJUSTED_SUMMARY", default = "false")]
+    pub fee_adjusted_summary: bool,
+    /// Combine levels of exchanges at the same price into one with the breakdown by exchange
+    #[envconfig(from = "CONSOLIDATED_SUMMARY", default = "false")]
+    pub consolidated_summary: bool,
     #[envconfig(from = "SUMMARY_SIZE", default = "10")]
     pub summary_size: usize,
+    /// Books not updated for this period are evicted from the summary, never if not set
+    #[envconfig(from = "STALE_BOOK_THRESHOLD_MS")]
+    pub stale_book_threshold_ms: Option<u64>,
+    #[envconfig(from = "RECONNECT_INITIAL_DELAY_MS", default = "500")]
+    pub reconnect_initial_delay_ms: u64,
+    #[envconfig(from = "RECONNECT_MAX_DELAY_MS", default = "30000")]
+    pub reconnect_max_delay_ms: u64,
+    /// Consecutive failed reconnects after which an exchange is given up, unlimited if not set
+    #[envconfig(from = "RECONNECT_MAX_RETRIES")]
+    pub reconnect_max_retries: Option<u32>,
+}
+
+impl Config {
+    pub fn reconnect_backoff(&self) -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(self.reconnect_initial_delay_ms),
+            max_delay: Duration::from_millis(self.reconnect_max_delay_ms),
+            max_retries: self.reconnect_max_retries,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -53,4 +342,141 @@ mod tests {
             }),
         );
     }
+
+    #[test]
+    fn parse_additional_pairs() {
+        assert_eq!(
+            Config::init_from_hashmap(&hashmap! {
+                "ADDITIONAL_PAIRS".to_owned() => "eth/btc, LTC/BTC".to_owned()
+            })
+            .unwrap()
+            .additional_pairs,
+            Pairs(vec![Pair::new("eth", "btc"), Pair::new("ltc", "btc")])
+        );
+
+        assert_eq!(
+            Config::init_from_hashmap(&hashmap! {
+                "ADDITIONAL_PAIRS".to_owned() => "ethbtc".to_owned()
+            }),
+            Err(envconfig::Error::ParseError {
+                name: "ADDITIONAL_PAIRS"
+            }),
+        );
+    }
+
+    #[test]
+    fn parse_exchanges() {
+        assert_eq!(
+            Config::init_from_hashmap(&hashmap! {}).unwrap().exchanges,
+            Exchanges(vec!["binance".to_owned(), "bitstamp".to_owned()])
+        );
+
+        assert_eq!(
+            Config::init_from_hashmap(&hashmap! {
+                "EXCHANGES".to_owned() => "Binance, coinbase,".to_owned()
+            })
+            .unwrap()
+            .exchanges,
+            Exchanges(vec!["binance".to_owned(), "coinbase".to_owned()])
+        );
+    }
+
+    #[test]
+    fn parse_bybit_depth() {
+        let config = Config::init_from_hashmap(&hashmap! {}).unwrap();
+        assert_eq!(config.bybit_depth, exchanges::bybit::Depth::_50);
+        assert_eq!(config.bybit_ping_interval_secs, 20);
+
+        assert_eq!(
+            Config::init_from_hashmap(&hashmap! {
+                "BYBIT_DEPTH".to_owned() => "200".to_owned()
+            })
+            .unwrap()
+            .bybit_depth,
+            exchanges::bybit::Depth::_200
+        );
+        assert!(Config::init_from_hashmap(&hashmap! {
+            "BYBIT_DEPTH".to_owned() => "100".to_owned()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn parse_quote_conversion() {
+        let config = Config::init_from_hashmap(&hashmap! {}).unwrap();
+        assert_eq!(
+            config.quote_substitutes.quote_currency("bitstamp", "usdt"),
+            "usdt"
+        );
+        assert_eq!(config.fx_rates, FxRates::default());
+        assert_eq!(config.fx_rate_sources, FxRateSources::default());
+
+        let config = Config::init_from_hashmap(&hashmap! {
+            "QUOTE_SUBSTITUTES".to_owned() => "Kraken:usdt=USD, kraken:usdc=usd".to_owned(),
+            "FX_RATES".to_owned() => "usdc/usd=0.9998".to_owned(),
+            "FX_RATE_SOURCES".to_owned() => "usdt/usd@Kraken".to_owned(),
+        })
+        .unwrap();
+        assert_eq!(
+            config.quote_substitutes,
+            QuoteSubstitutes(vec![
+                ("kraken".to_owned(), "usdt".to_owned(), "usd".to_owned()),
+                ("kraken".to_owned(), "usdc".to_owned(), "usd".to_owned()),
+            ])
+        );
+        assert_eq!(
+            config.fx_rates,
+            FxRates(vec![(
+                Pair::new("usdc", "usd"),
+                Decimal::from_str("0.9998").unwrap()
+            )])
+        );
+        assert_eq!(
+            config.fx_rate_sources,
+            FxRateSources(vec![(Pair::new("usdt", "usd"), "kraken".to_owned())])
+        );
+        assert_eq!(
+            config.quote_substitutes.quote_currency("kraken", "btc"),
+            "btc"
+        );
+        assert_eq!(
+            config.quote_substitutes.quote_currency("binance", "usdt"),
+            "usdt"
+        );
+
+        for (name, value) in [
+            ("QUOTE_SUBSTITUTES", "bitstamp=usd"),
+            ("FX_RATES", "usdt/usd"),
+            ("FX_RATE_SOURCES", "usdt/usd@"),
+        ] {
+            assert_eq!(
+                Config::init_from_hashmap(&hashmap! { name.to_owned() => value.to_owned() }),
+                Err(envconfig::Error::ParseError { name }),
+            );
+        }
+    }
+
+    #[test]
+    fn parse_taker_fees() {
+        assert_eq!(
+            Config::init_from_hashmap(&hashmap! {
+                "TAKER_FEES".to_owned() => "Binance=0.001, kraken=0.0026".to_owned()
+            })
+            .unwrap()
+            .taker_fees,
+            TakerFees(hashmap! {
+                "binance".to_owned() => Decimal::from_str("0.001").unwrap(),
+                "kraken".to_owned() => Decimal::from_str("0.0026").unwrap(),
+            })
+        );
+
+        for value in ["binance", "binance=-0.001", "binance=1"] {
+            assert_eq!(
+                Config::init_from_hashmap(&hashmap! {
+                    "TAKER_FEES".to_owned() => value.to_owned()
+                }),
+                Err(envconfig::Error::ParseError { name: "TAKER_FEES" }),
+            );
+        }
+    }
 }
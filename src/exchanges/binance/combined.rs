@@ -0,0 +1,445 @@
+//! Partial books of many pairs multiplexed over one websocket
+//!
+//! Streams are added to the connection by `SUBSCRIBE` method calls, events of the combined
+//! endpoint are wrapped as `{"stream": <name>, "data": <payload>}` and demultiplexed by the name.
+//! The connection is opened by the first subscription and reopened by the first one after it's lost.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_tungstenite::{
+    tokio::{connect_async as ws_connect, ConnectStream},
+    tungstenite::Message,
+    WebSocketStream,
+};
+use futures_util::sink::SinkExt;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use super::{deserialize_partial_depth, Depth, Error, Market};
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, OrderBook},
+};
+
+/// Request to add a stream to the connection
+struct Subscription {
+    stream: String,
+    sender: mpsc::Sender<Result<OrderBook, Error>>,
+    /// Completed by the response to `SUBSCRIBE` call
+    ack: oneshot::Sender<Result<(), Error>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Response {
+    Event {
+        stream: String,
+        data: serde_json::Value,
+    },
+    Error {
+        id: Option<u64>,
+        error: serde_json::Value,
+    },
+    Result {
+        id: u64,
+    },
+}
+
+/// Requests of subscriptions and websocket events are handled until the connection is lost
+///
+/// A stream is subscribed by its first subscriber and unsubscribed once the last one is gone,
+/// other subscribers of it share the events. Lost connection ends streams of all subscribed pairs
+async fn multiplex(
+    mut ws: WebSocketStream<ConnectStream>,
+    mut subscriptions: mpsc::Receiver<Subscription>,
+    market: Market,
+) {
+    let mut streams = HashMap::<String, Vec<mpsc::Sender<Result<OrderBook, Error>>>>::new();
+    let mut pending = HashMap::<u64, Subscription>::new();
+    let mut next_id = 1u64;
+
+    loop {
+        let event = tokio::select! {
+            subscription = subscriptions.recv() => {
+                let subscription = match subscription {
+                    Some(subscription) => subscription,
+                    None => return,
+                };
+
+                if let Some(senders) = streams.get_mut(&subscription.stream) {
+                    debug!("Stream {} is already subscribed, share it", subscription.stream);
+                    if subscription.ack.send(Ok(())).is_ok() {
+                        senders.push(subscription.sender);
+                    }
+                    continue;
+                }
+
+                let id = next_id;
+                next_id += 1;
+
+                let request = json!({
+                    "method": "SUBSCRIBE",
+                    "params": [subscription.stream],
+                    "id": id,
+                });
+                info!("Send subscribe for {}", subscription.stream);
+
+                if let Err(err) = ws.send(Message::Text(request.to_string())).await {
+                    error!("Error while subscribe to binance: {err:?}");
+                    let _ = subscription.ack.send(Err(Error::from(err)));
+                    return;
+                }
+                pending.insert(id, subscription);
+                continue;
+            }
+            event = ws.next() => match event {
+                Some(event) => event,
+                None => return,
+            },
+        };
+
+        let text = match event {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+            Ok(other) => {
+                warn!("Unexpected message: {other:?}");
+                continue;
+            }
+            Err(err) => {
+                error!("Error while handle binance ws: {err:?}");
+                return;
+            }
+        };
+
+        match serde_json::from_str::<'_, Response>(&text) {
+            Ok(Response::Event { stream, data }) => {
+                let senders = match streams.get_mut(&stream) {
+                    Some(senders) => senders,
+                    None => {
+                        trace!("Skip event of not subscribed {stream}");
+                        continue;
+                    }
+                };
+
+                senders.retain(|sender| {
+                    let order_book = deserialize_partial_depth(market, &data).map_err(Error::from);
+                    match sender.try_send(order_book) {
+                        Ok(()) => true,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            trace!("Skip book of {stream}, the receiver lags");
+                            true
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => false,
+                    }
+                });
+
+                if senders.is_empty() {
+                    info!("Stream {stream} is not received anymore, unsubscribe");
+                    streams.remove(&stream);
+
+                    let request = json!({
+                        "method": "UNSUBSCRIBE",
+                        "params": [stream],
+                        "id": next_id,
+                    });
+                    next_id += 1;
+                    if let Err(err) = ws.send(Message::Text(request.to_string())).await {
+                        error!("Error while unsubscribe from binance: {err:?}");
+                        return;
+                    }
+                }
+            }
+            Ok(Response::Error { id, .. }) => match id.and_then(|id| pending.remove(&id)) {
+                Some(subscription) => {
+                    let _ = subscription
+                        .ack
+                        .send(Err(Error::SubscriptionNotSuccess { response: text }));
+                }
+                None => warn!("Binance error {text}"),
+            },
+            Ok(Response::Result { id }) => {
+                if let Some(Subscription {
+                    stream,
+                    sender,
+                    ack,
+                }) = pending.remove(&id)
+                {
+                    if ack.send(Ok(())).is_ok() {
+                        streams.entry(stream).or_default().push(sender);
+                    }
+                }
+            }
+            Err(error) => error!("{error:?}"),
+        }
+    }
+}
+
+/// Binance source of partial books sharing one websocket of the combined endpoint for all pairs
+///
+/// Handles are cheap to clone, clones share the connection
+#[derive(Clone)]
+pub struct BinanceCombined {
+    /// Combined endpoint, e.g. `wss://stream.binance.com:443/stream`
+    ws_url: Url,
+    market: Market,
+    depth: Depth,
+    connection: Arc<Mutex<Option<mpsc::Sender<Subscription>>>>,
+}
+
+impl BinanceCombined {
+    pub fn new(ws_url: Url, market: Market, depth: Depth) -> Self {
+        Self {
+            ws_url,
+            market,
+            depth,
+            connection: Arc::default(),
+        }
+    }
+
+    /// Sender of subscriptions to the current connection, connects if there is none
+    async fn connection(&self) -> Result<mpsc::Sender<Subscription>, Error> {
+        let mut connection = self.connection.lock().await;
+        if let Some(subscriptions) = connection.as_ref().filter(|sender| !sender.is_closed()) {
+            return Ok(subscriptions.clone());
+        }
+
+        info!("Connect to binance by {}", self.ws_url);
+
+        let (ws, _) = ws_connect(self.ws_url.clone()).await?;
+        let (subscriptions, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+        tokio::spawn(multiplex(ws, receiver, self.market).instrument(span!(
+            Level::TRACE,
+            "Multiplex binance streams",
+            market = ?self.market
+        )));
+
+        *connection = Some(subscriptions.clone());
+        Ok(subscriptions)
+    }
+
+    /// Add `{symbol}@depth{depth}` stream to the connection, the symbol is lowercased for its name
+    async fn subscribe(
+        &self,
+        symbol: &str,
+    ) -> Result<ReceiverStream<Result<OrderBook, Error>>, Error> {
+        let stream = format!(
+            "{symbol}@depth{depth}",
+            symbol = symbol.to_lowercase(),
+            depth = u8::from(self.depth)
+        );
+
+        let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+        let (ack, acked) = oneshot::channel();
+
+        self.connection()
+            .await?
+            .send(Subscription {
+                stream,
+                sender,
+                ack,
+            })
+            .await
+            .map_err(|_| Error::ConnectionClosed)?;
+        acked.await.map_err(|_| Error::ConnectionClosed)??;
+
+        Ok(ReceiverStream::new(receiver))
+    }
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for BinanceCombined {
+    type Error = Error;
+    type OrderBooksStream = ReceiverStream<Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        self.subscribe(&self.market.symbol(base_currency, quote_currency))
+            .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        self.subscribe(&instrument.symbol).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    fn event(stream: &str, bids: &[[&str; 2]], asks: &[[&str; 2]]) -> String {
+        json!({
+            "stream": stream,
+            "data": { "lastUpdateId": 160, "bids": bids, "asks": asks },
+        })
+        .to_string()
+    }
+
+    async fn receive_subscribe(ws: &mut stand_in::ServerWebSocket) -> serde_json::Value {
+        serde_json::from_str(&stand_in::receive_text(ws).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_streams_share_connection() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let ws_url = stand_in::serve_ws({
+            let connections = connections.clone();
+            move |mut ws| {
+                connections.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    for stream in ["btcusdt@depth10", "ethusdt@depth10"] {
+                        let subscribe = receive_subscribe(&mut ws).await;
+                        assert_eq!(subscribe["method"], "SUBSCRIBE");
+                        assert_eq!(subscribe["params"], json!([stream]));
+                        ws.send(Message::Text(
+                            json!({ "result": null, "id": subscribe["id"] }).to_string(),
+                        ))
+                        .await
+                        .unwrap();
+                    }
+
+                    stand_in::send_frames(
+                        ws,
+                        vec![
+                            event("ethusdt@depth10", &[["10", "1"]], &[["11", "1"]]),
+                            event("btcusdt@depth10", &[["100", "1"]], &[["101", "1"]]),
+                            event("xrpusdt@depth10", &[["1", "1"]], &[["2", "1"]]),
+                            event("btcusdt@depth10", &[["100", "2"]], &[["101", "2"]]),
+                        ],
+                    )
+                    .await
+                }
+            }
+        })
+        .await;
+
+        let source = BinanceCombined::new(ws_url, Market::Spot, Depth::_10);
+        let mut btc = source.get_order_books_stream("btc", "usdt").await.unwrap();
+        let mut eth = source
+            .clone()
+            .get_order_books_stream("eth", "usdt")
+            .await
+            .unwrap();
+
+        let order_book = eth.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("10", "1")]);
+        assert_eq!(order_book.asks, vec![level("11", "1")]);
+
+        let order_book = btc.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1")]);
+        let order_book = btc.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "2")]);
+        assert_eq!(order_book.asks, vec![level("101", "2")]);
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_error() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            let subscribe = receive_subscribe(&mut ws).await;
+            ws.send(Message::Text(
+                json!({
+                    "error": { "code": 2, "msg": "Invalid request: unknown stream" },
+                    "id": subscribe["id"],
+                })
+                .to_string(),
+            ))
+            .await
+            .unwrap();
+            stand_in::send_frames(ws, vec![]).await
+        })
+        .await;
+
+        let source = BinanceCombined::new(ws_url, Market::Spot, Depth::_10);
+        assert!(matches!(
+            source.get_order_books_stream("abc", "def").await,
+            Err(Error::SubscriptionNotSuccess { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_same_stream_subscribed_twice() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+        let dropped = Arc::new(tokio::sync::Notify::new());
+        let ws_url = stand_in::serve_ws({
+            let dropped = dropped.clone();
+            move |mut ws| {
+                let requests = requests.clone();
+                let dropped = dropped.clone();
+                async move {
+                    for _ in 0..2 {
+                        let subscribe = receive_subscribe(&mut ws).await;
+                        ws.send(Message::Text(
+                            json!({ "result": null, "id": subscribe["id"] }).to_string(),
+                        ))
+                        .await
+                        .unwrap();
+                        requests.send(subscribe).unwrap();
+                    }
+
+                    ws.send(Message::Text(event(
+                        "btcusdt@depth10",
+                        &[["100", "1"]],
+                        &[["101", "1"]],
+                    )))
+                    .await
+                    .unwrap();
+
+                    dropped.notified().await;
+                    ws.send(Message::Text(event(
+                        "btcusdt@depth10",
+                        &[["100", "2"]],
+                        &[["101", "2"]],
+                    )))
+                    .await
+                    .unwrap();
+                    requests.send(receive_subscribe(&mut ws).await).unwrap();
+
+                    stand_in::send_frames(ws, vec![]).await
+                }
+            }
+        })
+        .await;
+
+        let source = BinanceCombined::new(ws_url, Market::Spot, Depth::_10);
+        let first = source.get_order_books_stream("btc", "usdt").await.unwrap();
+        let mut second = source.get_order_books_stream("btc", "usdt").await.unwrap();
+        drop(first);
+        let _eth = source.get_order_books_stream("eth", "usdt").await.unwrap();
+
+        // The first subscriber is gone, the second one still receives the stream
+        let order_book = second.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1")]);
+
+        drop(second);
+        dropped.notify_one();
+
+        let mut requests = vec![];
+        for _ in 0..3 {
+            let request = received.recv().await.unwrap();
+            requests.push((request["method"].clone(), request["params"].clone()));
+        }
+        assert_eq!(
+            requests,
+            vec![
+                (json!("SUBSCRIBE"), json!(["btcusdt@depth10"])),
+                (json!("SUBSCRIBE"), json!(["ethusdt@depth10"])),
+                (json!("UNSUBSCRIBE"), json!(["btcusdt@depth10"])),
+            ]
+        );
+    }
+}
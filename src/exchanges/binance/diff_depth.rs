@@ -0,0 +1,541 @@
+//! Local order book maintained from `@depth@100ms` diff events
+//!
+//! Follows the binance guide "How to manage a local order book correctly":
+//! events are buffered by the websocket while a REST snapshot is fetched,
+//! events older than the snapshot are dropped and every next event must
+//! continue the previous one, otherwise the book is resynchronized from a new snapshot.
+//!
+//! Futures events carry `pu`, the final update id of the previous event, which is
+//! checked instead, as update ids of futures events are not consecutive.
+
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use super::{Error, Market};
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel, Side},
+};
+
+#[derive(Debug, Deserialize)]
+pub(super) struct Snapshot {
+    #[serde(rename = "lastUpdateId")]
+    pub last_update_id: u64,
+    #[serde(flatten)]
+    pub order_book: OrderBook,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct DepthUpdate {
+    #[serde(rename = "U")]
+    pub first_update_id: u64,
+    #[serde(rename = "u")]
+    pub final_update_id: u64,
+    /// Final update id of the previous event, sent by futures markets only
+    #[serde(rename = "pu", default)]
+    pub previous_update_id: Option<u64>,
+    #[serde(rename = "b")]
+    pub bids: Vec<PriceLevel>,
+    #[serde(rename = "a")]
+    pub asks: Vec<PriceLevel>,
+}
+
+/// Local book with the id of the last applied update
+#[derive(Debug)]
+pub(super) struct DiffDepthBook {
+    book: LocalOrderBook,
+    last_update_id: u64,
+    /// Whether at least one event has been applied over the snapshot
+    synced: bool,
+}
+
+impl From<Snapshot> for DiffDepthBook {
+    fn from(snapshot: Snapshot) -> Self {
+        Self {
+            book: LocalOrderBook::from(snapshot.order_book),
+            last_update_id: snapshot.last_update_id,
+            synced: false,
+        }
+    }
+}
+
+impl DiffDepthBook {
+    /// Apply update to the book
+    ///
+    /// Returns `Ok(false)` if the update is older than the book and was dropped,
+    /// [`Error::OutOfSync`] or [`Error::PreviousUpdateMismatch`] if some updates were lost
+    /// and the book must be resynchronized
+    pub fn apply(&mut self, update: DepthUpdate) -> Result<bool, Error> {
+        let is_newer = match update.previous_update_id {
+            None => self.check_spot_continuation(&update)?,
+            Some(previous_update_id) => {
+                self.check_futures_continuation(&update, previous_update_id)?
+            }
+        };
+        if !is_newer {
+            return Ok(false);
+        }
+
+        update
+            .bids
+            .into_iter()
+            .for_each(|level| self.book.apply(Side::Bid, level));
+        update
+            .asks
+            .into_iter()
+            .for_each(|level| self.book.apply(Side::Ask, level));
+
+        self.last_update_id = update.final_update_id;
+        self.synced = true;
+
+        Ok(true)
+    }
+
+    pub fn top(&self, depth: usize) -> OrderBook {
+        self.book.top(depth)
+    }
+
+    /// Spot events are dropped up to `lastUpdateId`, the first one must cover `lastUpdateId + 1`
+    fn check_spot_continuation(&self, update: &DepthUpdate) -> Result<bool, Error> {
+        if update.final_update_id <= self.last_update_id {
+            return Ok(false);
+        }
+
+        let expected = self.last_update_id + 1;
+        let is_continuation = if self.synced {
+            update.first_update_id == expected
+        } else {
+            update.first_update_id <= expected
+        };
+        if !is_continuation {
+            return Err(Error::OutOfSync {
+                expected,
+                first_update_id: update.first_update_id,
+            });
+        }
+
+        Ok(true)
+    }
+
+    /// Futures events are dropped before `lastUpdateId`, the first one must cover `lastUpdateId`
+    /// and every next one must follow the previous by `pu`
+    fn check_futures_continuation(
+        &self,
+        update: &DepthUpdate,
+        previous_update_id: u64,
+    ) -> Result<bool, Error> {
+        if update.final_update_id < self.last_update_id
+            || (self.synced && update.final_update_id == self.last_update_id)
+        {
+            return Ok(false);
+        }
+
+        if self.synced && previous_update_id != self.last_update_id {
+            return Err(Error::PreviousUpdateMismatch {
+                expected: self.last_update_id,
+                previous_update_id,
+            });
+        }
+        if !self.synced && update.first_update_id > self.last_update_id {
+            return Err(Error::OutOfSync {
+                expected: self.last_update_id,
+                first_update_id: update.first_update_id,
+            });
+        }
+
+        Ok(true)
+    }
+}
+
+async fn fetch_snapshot(
+    rest_url: &Url,
+    market: Market,
+    symbol: &str,
+    snapshot_limit: u16,
+) -> Result<Snapshot, Error> {
+    let mut url = rest_url.join(market.depth_path())?;
+    url.query_pairs_mut()
+        .append_pair("symbol", symbol)
+        .append_pair("limit", &snapshot_limit.to_string());
+
+    debug!("Fetch binance snapshot by {url}");
+
+    Ok(reqwest::get(url)
+        .await?
+        .error_for_status()?
+        .json::<Snapshot>()
+        .await?)
+}
+
+pub async fn get_diff_depth_stream(
+    mut ws_url: Url,
+    rest_url: Url,
+    market: Market,
+    symbol: &str,
+    snapshot_limit: u16,
+    depth: usize,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let symbol = symbol.to_owned();
+
+    ws_url
+        .path_segments_mut()
+        .map_err(|()| Error::UrlCannotBeBase)?
+        .push(format!("{}@depth@100ms", symbol.to_lowercase()).as_str());
+
+    info!("Connect to binance by {ws_url}");
+
+    let (mut ws, _) = ws_connect(ws_url).await?;
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+
+    tokio::spawn(
+        async move {
+            'resync: loop {
+                // Events received meanwhile are buffered by the websocket
+                let mut book =
+                    match fetch_snapshot(&rest_url, market, &symbol, snapshot_limit).await {
+                        Ok(snapshot) => DiffDepthBook::from(snapshot),
+                        Err(err) => {
+                            error!("Error while fetch binance snapshot: {err:?}");
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                    };
+
+                if sender.send(Ok(book.top(depth))).await.is_err() {
+                    return;
+                }
+
+                while let Some(event) = ws.next().await {
+                    let update = match event {
+                        Ok(Message::Text(text)) => serde_json::from_str::<DepthUpdate>(&text),
+                        Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+                        Ok(other) => {
+                            warn!("Unexpected message: {other:?}");
+                            continue;
+                        }
+                        Err(err) => {
+                            error!("Error while handle binance ws: {err:?}");
+                            let _ = sender.send(Err(Error::from(err))).await;
+                            return;
+                        }
+                    };
+
+                    let order_book = match update
+                        .map_err(Error::from)
+                        .and_then(|update| book.apply(update))
+                    {
+                        Ok(true) => Ok(book.top(depth)),
+                        Ok(false) => continue,
+                        Err(
+                            err @ (Error::OutOfSync { .. } | Error::PreviousUpdateMismatch { .. }),
+                        ) => {
+                            warn!("Binance book out of sync: {err}, resync");
+                            continue 'resync;
+                        }
+                        Err(err) => Err(err),
+                    };
+
+                    if sender.send(order_book).await.is_err() {
+                        return;
+                    }
+                }
+
+                return;
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+/// Binance source that maintains the full depth book from diff events
+pub struct BinanceDiffDepth {
+    pub ws_url: Url,
+    pub rest_url: Url,
+    pub market: Market,
+    /// Number of levels requested for the REST snapshot, up to 5000
+    pub snapshot_limit: u16,
+    /// Levels per side emitted after each event
+    pub depth: usize,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for BinanceDiffDepth {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_diff_depth_stream(
+            self.ws_url.clone(),
+            self.rest_url.clone(),
+            self.market,
+            &self.market.symbol(base_currency, quote_currency),
+            self.snapshot_limit,
+            self.depth,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_diff_depth_stream(
+            self.ws_url.clone(),
+            self.rest_url.clone(),
+            self.market,
+            &instrument.symbol,
+            self.snapshot_limit,
+            self.depth,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::assert_matches::assert_matches;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    fn depth_update(
+        first_update_id: u64,
+        final_update_id: u64,
+        bids: &[[&str; 2]],
+        asks: &[[&str; 2]],
+    ) -> String {
+        json!({
+            "e": "depthUpdate",
+            "E": 1672515782136u64,
+            "s": "BTCUSDT",
+            "U": first_update_id,
+            "u": final_update_id,
+            "b": bids,
+            "a": asks,
+        })
+        .to_string()
+    }
+
+    fn futures_depth_update(
+        first_update_id: u64,
+        final_update_id: u64,
+        previous_update_id: u64,
+        bids: &[[&str; 2]],
+        asks: &[[&str; 2]],
+    ) -> String {
+        json!({
+            "e": "depthUpdate",
+            "E": 1672515782136u64,
+            "T": 1672515782134u64,
+            "s": "BTCUSD_PERP",
+            "ps": "BTCUSD",
+            "U": first_update_id,
+            "u": final_update_id,
+            "pu": previous_update_id,
+            "b": bids,
+            "a": asks,
+        })
+        .to_string()
+    }
+
+    fn snapshot(last_update_id: u64, bids: &[[&str; 2]], asks: &[[&str; 2]]) -> String {
+        json!({
+            "lastUpdateId": last_update_id,
+            "bids": bids,
+            "asks": asks,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_first_update_must_overlap_snapshot() {
+        let mut book = DiffDepthBook::from(
+            serde_json::from_str::<Snapshot>(&snapshot(100, &[], &[])).unwrap(),
+        );
+
+        let update = serde_json::from_str(&depth_update(102, 105, &[], &[])).unwrap();
+        assert_matches!(
+            book.apply(update),
+            Err(Error::OutOfSync {
+                expected: 101,
+                first_update_id: 102
+            })
+        );
+
+        let update = serde_json::from_str(&depth_update(90, 100, &[], &[])).unwrap();
+        assert_matches!(book.apply(update), Ok(false));
+
+        let update = serde_json::from_str(&depth_update(95, 101, &[], &[])).unwrap();
+        assert_matches!(book.apply(update), Ok(true));
+    }
+
+    #[test]
+    fn test_futures_update_must_follow_previous() {
+        let mut book = DiffDepthBook::from(
+            serde_json::from_str::<Snapshot>(&snapshot(100, &[], &[])).unwrap(),
+        );
+
+        let update = serde_json::from_str(&futures_depth_update(101, 105, 100, &[], &[])).unwrap();
+        assert_matches!(
+            book.apply(update),
+            Err(Error::OutOfSync {
+                expected: 100,
+                first_update_id: 101
+            })
+        );
+
+        let update = serde_json::from_str(&futures_depth_update(90, 99, 89, &[], &[])).unwrap();
+        assert_matches!(book.apply(update), Ok(false));
+
+        let update = serde_json::from_str(&futures_depth_update(95, 100, 94, &[], &[])).unwrap();
+        assert_matches!(book.apply(update), Ok(true));
+
+        // Update ids of futures events are not consecutive
+        let update = serde_json::from_str(&futures_depth_update(104, 110, 100, &[], &[])).unwrap();
+        assert_matches!(book.apply(update), Ok(true));
+
+        let update = serde_json::from_str(&futures_depth_update(115, 120, 112, &[], &[])).unwrap();
+        assert_matches!(
+            book.apply(update),
+            Err(Error::PreviousUpdateMismatch {
+                expected: 110,
+                previous_update_id: 112
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diff_depth_stream_resync() {
+        let (rest_url, requests) = stand_in::serve_http(vec![
+            snapshot(
+                100,
+                &[["100", "1"], ["99", "2"]],
+                &[["101", "1"], ["102", "2"]],
+            ),
+            snapshot(120, &[["97", "1"]], &[["103", "1"]]),
+        ])
+        .await;
+
+        let ws_url = stand_in::serve_ws(|ws| {
+            stand_in::send_frames(
+                ws,
+                vec![
+                    // Older than the snapshot, dropped
+                    depth_update(95, 100, &[["100", "10"]], &[]),
+                    depth_update(99, 102, &[["100", "3"]], &[["101", "0"]]),
+                    depth_update(103, 104, &[["98", "1"]], &[]),
+                    // Gap, leads to resync with the second snapshot
+                    depth_update(110, 111, &[["50", "1"]], &[]),
+                    depth_update(119, 121, &[["96", "1"]], &[]),
+                ],
+            )
+        })
+        .await;
+
+        let source = BinanceDiffDepth {
+            ws_url,
+            rest_url,
+            market: Market::Spot,
+            snapshot_limit: 1000,
+            depth: 2,
+        };
+        let mut stream = source.get_order_books_stream("btc", "usdt").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1"), level("99", "2")]);
+        assert_eq!(order_book.asks, vec![level("101", "1"), level("102", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "3"), level("99", "2")]);
+        assert_eq!(order_book.asks, vec![level("102", "2")]);
+
+        // Only the top levels are emitted
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "3"), level("99", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1")]);
+        assert_eq!(order_book.asks, vec![level("103", "1")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1"), level("96", "1")]);
+        assert_eq!(order_book.asks, vec![level("103", "1")]);
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["/api/v3/depth?symbol=BTCUSDT&limit=1000"; 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coin_m_diff_depth_stream() {
+        let (rest_url, requests) = stand_in::serve_http(vec![
+            snapshot(100, &[["100", "1"]], &[["101", "1"]]),
+            snapshot(130, &[["97", "1"]], &[["103", "1"]]),
+        ])
+        .await;
+
+        let ws_url = stand_in::serve_ws(|ws| {
+            stand_in::send_frames(
+                ws,
+                vec![
+                    futures_depth_update(90, 98, 89, &[["100", "10"]], &[]),
+                    futures_depth_update(99, 100, 98, &[["100", "2"]], &[]),
+                    futures_depth_update(103, 107, 100, &[["99", "1"]], &[]),
+                    // `pu` refers to an event which was not received, leads to resync
+                    futures_depth_update(120, 125, 115, &[["50", "1"]], &[]),
+                    futures_depth_update(126, 130, 125, &[], &[]),
+                    futures_depth_update(131, 135, 130, &[["96", "1"]], &[]),
+                ],
+            )
+        })
+        .await;
+
+        let source = BinanceDiffDepth {
+            ws_url,
+            rest_url,
+            market: Market::CoinM,
+            snapshot_limit: 1000,
+            depth: 2,
+        };
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "2"), level("99", "1")]);
+        assert_eq!(order_book.asks, vec![level("101", "1")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1")]);
+        assert_eq!(order_book.asks, vec![level("103", "1")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1"), level("96", "1")]);
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["/dapi/v1/depth?symbol=BTCUSD_PERP&limit=1000"; 2]
+        );
+    }
+}
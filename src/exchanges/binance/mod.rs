@@ -0,0 +1,196 @@
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use serde::{Deserialize, Deserializer};
+use tokio_stream::{Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, OrderBook, PriceLevel},
+};
+
+mod combined;
+mod diff_depth;
+pub use combined::BinanceCombined;
+pub use diff_depth::{get_diff_depth_stream, BinanceDiffDepth};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error("The input URL cannot be a base URL. Please provide a full URL.")]
+    UrlCannotBeBase,
+    #[error("Depth update {first_update_id} does not continue the book, expected {expected}")]
+    OutOfSync { expected: u64, first_update_id: u64 },
+    #[error("Connection of combined streams is closed")]
+    ConnectionClosed,
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+    #[error("Depth update follows {previous_update_id}, expected to follow {expected}")]
+    PreviousUpdateMismatch {
+        expected: u64,
+        previous_update_id: u64,
+    },
+}
+
+/// Market of the books, futures markets are served by `fstream`/`dstream` and `fapi`/`dapi` endpoints
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub enum Market {
+    #[default]
+    Spot,
+    /// USD-M futures, perpetual contracts margined in USDT or BUSD
+    UsdM,
+    /// COIN-M futures, perpetual contracts margined in the base currency
+    CoinM,
+}
+impl Market {
+    /// Symbol of the pair, e.g. `BTCUSDT`, COIN-M contracts are named with `_PERP` suffix
+    pub fn symbol(&self, base_currency: &str, quote_currency: &str) -> String {
+        let symbol = format!(
+            "{}{}",
+            base_currency.to_uppercase(),
+            quote_currency.to_uppercase()
+        );
+        match self {
+            Market::Spot | Market::UsdM => symbol,
+            Market::CoinM => format!("{symbol}_PERP"),
+        }
+    }
+
+    /// Path of the REST depth snapshot endpoint
+    fn depth_path(&self) -> &'static str {
+        match self {
+            Market::Spot => "api/v3/depth",
+            Market::UsdM => "fapi/v1/depth",
+            Market::CoinM => "dapi/v1/depth",
+        }
+    }
+}
+
+/// Partial book of futures markets, sent as `depthUpdate` event with `b`/`a` levels
+#[derive(Debug, Deserialize)]
+struct FuturesPartialDepth {
+    #[serde(rename = "b")]
+    bids: Vec<PriceLevel>,
+    #[serde(rename = "a")]
+    asks: Vec<PriceLevel>,
+}
+
+fn parse_partial_depth(market: Market, text: &str) -> Result<OrderBook, serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_str(text);
+    let order_book = deserialize_partial_depth(market, &mut deserializer)?;
+    deserializer.end()?;
+    Ok(order_book)
+}
+
+fn deserialize_partial_depth<'de, D>(market: Market, deserializer: D) -> Result<OrderBook, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match market {
+        Market::Spot => OrderBook::deserialize(deserializer),
+        Market::UsdM | Market::CoinM => FuturesPartialDepth::deserialize(deserializer)
+            .map(|FuturesPartialDepth { bids, asks }| OrderBook { bids, asks }),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Depth {
+    _5,
+    _10,
+    _20,
+}
+impl From<Depth> for u8 {
+    fn from(value: Depth) -> Self {
+        match value {
+            Depth::_5 => 5,
+            Depth::_10 => 10,
+            Depth::_20 => 20,
+        }
+    }
+}
+
+/// Partial book of `{symbol}@depth{depth}` stream, the symbol is lowercased for the stream name
+pub async fn get_summary_stream(
+    mut url: Url,
+    market: Market,
+    symbol: &str,
+    depth: Depth,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    url.path_segments_mut()
+        .map_err(|()| Error::UrlCannotBeBase)?
+        .push(
+            format!(
+                "{symbol}@depth{depth}",
+                symbol = symbol.to_lowercase(),
+                depth = u8::from(depth)
+            )
+            .as_str(),
+        );
+
+    info!("Connect to binance by {url}");
+
+    Ok(ws_connect(url)
+        .await?
+        .0
+        .filter_map(move |event| match event {
+            Ok(Message::Text(text)) => match parse_partial_depth(market, &text) {
+                Ok(order_book) => Some(Ok(order_book)),
+                Err(error) => Some(Err(Error::Format(error))),
+            },
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => None,
+            Ok(other) => {
+                warn!("Unexpected message: {other:?}");
+                None
+            }
+            Err(err) => {
+                error!("Error while handle binance ws: {err:?}");
+                Some(Err(Error::from(err)))
+            }
+        }))
+}
+
+pub struct Binance {
+    pub ws_url: Url,
+    pub market: Market,
+    pub depth: Depth,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Binance {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_summary_stream(
+            self.ws_url.clone(),
+            self.market,
+            &self.market.symbol(base_currency, quote_currency),
+            self.depth,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_summary_stream(
+            self.ws_url.clone(),
+            self.market,
+            &instrument.symbol,
+            self.depth,
+        )
+        .await
+    }
+}
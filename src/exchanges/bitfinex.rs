@@ -0,0 +1,635 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::sink::SinkExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Number};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel, Side},
+};
+
+/// `info` event codes asking to reconnect, the server is restarting or leaves maintenance
+const RECONNECT_CODES: [u32; 2] = [20051, 20061];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+    #[error("Connection is closed before the subscription is confirmed")]
+    ConnectionClosed,
+    #[error("No messages, even heartbeats, for {0:?}")]
+    HeartbeatTimeout(Duration),
+}
+
+/// Price aggregation of the book, `R0` is the raw book of individual orders
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Precision {
+    P0,
+    P1,
+    P2,
+    P3,
+    P4,
+    R0,
+}
+impl Precision {
+    fn name(&self) -> &'static str {
+        match self {
+            Precision::P0 => "P0",
+            Precision::P1 => "P1",
+            Precision::P2 => "P2",
+            Precision::P3 => "P3",
+            Precision::P4 => "P4",
+            Precision::R0 => "R0",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Length {
+    _1,
+    _25,
+    _100,
+    _250,
+}
+impl From<Length> for u16 {
+    fn from(value: Length) -> Self {
+        match value {
+            Length::_1 => 1,
+            Length::_25 => 25,
+            Length::_100 => 100,
+            Length::_250 => 250,
+        }
+    }
+}
+
+/// Bitfinex trading symbol of the pair, e.g. `tBTCUSD`
+///
+/// Symbols with currencies longer than three letters are separated by colon, e.g. `tDOGE:USD`,
+/// USDT is named `UST` by Bitfinex
+pub fn symbol(base_currency: &str, quote_currency: &str) -> String {
+    let currency = |currency: &str| match currency.to_uppercase().as_str() {
+        "USDT" => "UST".to_owned(),
+        other => other.to_owned(),
+    };
+    let base_currency = currency(base_currency);
+    let quote_currency = currency(quote_currency);
+
+    if base_currency.len() > 3 || quote_currency.len() > 3 {
+        format!("t{base_currency}:{quote_currency}")
+    } else {
+        format!("t{base_currency}{quote_currency}")
+    }
+}
+
+/// Bitfinex sends numbers instead of strings, their shortest representation is the sent one
+fn decimal(number: &Number) -> Result<Decimal, serde_json::Error> {
+    let number = number.to_string();
+    if number.contains('e') {
+        Decimal::from_scientific(&number)
+    } else {
+        Decimal::from_str_exact(&number)
+    }
+    .map_err(serde::de::Error::custom)
+}
+
+fn integer(number: &Number) -> Result<u64, serde_json::Error> {
+    number
+        .as_u64()
+        .ok_or_else(|| serde::de::Error::custom(format!("Expected integer, got {number}")))
+}
+
+/// Entry of the book, the side is the sign of the amount: positive for bids, negative for asks
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    /// `[price, count, amount]`, zero count removes the level
+    Level {
+        price: Decimal,
+        count: u64,
+        amount: Decimal,
+    },
+    /// `[order id, price, amount]`, zero price removes the order
+    Order {
+        id: u64,
+        price: Decimal,
+        amount: Decimal,
+    },
+}
+
+/// Entries are decoded according to precision of the subscription, which they don't carry
+#[derive(Debug)]
+struct RawEntry(Number, Number, Number);
+impl<'de> Deserialize<'de> for RawEntry {
+    fn deserialize<D>(deserializer: D) -> Result<RawEntry, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (first, second, third) = Deserialize::deserialize(deserializer)?;
+        Ok(RawEntry(first, second, third))
+    }
+}
+impl RawEntry {
+    fn decode(self, precision: Precision) -> Result<Entry, serde_json::Error> {
+        let RawEntry(first, second, third) = self;
+        Ok(match precision {
+            Precision::R0 => Entry::Order {
+                id: integer(&first)?,
+                price: decimal(&second)?,
+                amount: decimal(&third)?,
+            },
+            _ => Entry::Level {
+                price: decimal(&first)?,
+                count: integer(&second)?,
+                amount: decimal(&third)?,
+            },
+        })
+    }
+}
+
+/// Channel data is either a snapshot, a single update, or `hb` string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ChannelData {
+    Snapshot(Vec<RawEntry>),
+    Update(RawEntry),
+    Heartbeat(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Info {
+        code: Option<u32>,
+        msg: Option<String>,
+    },
+    Subscribed {
+        #[serde(rename = "chanId")]
+        channel_id: u64,
+    },
+    Error,
+    #[serde(other)]
+    Other,
+}
+
+/// Channel messages are `[chanId, data..]` arrays, events are objects
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Response {
+    Channel(Vec<serde_json::Value>),
+    Event(Event),
+}
+
+/// Book of aggregated levels, for raw books levels are sums of their orders
+#[derive(Debug, Default)]
+struct BitfinexBook {
+    book: LocalOrderBook,
+    /// Orders of the raw book by id, with the side and the level they contribute to
+    orders: HashMap<u64, (Side, PriceLevel)>,
+}
+impl BitfinexBook {
+    fn side(amount: &Decimal) -> Side {
+        if amount.is_sign_negative() {
+            Side::Ask
+        } else {
+            Side::Bid
+        }
+    }
+
+    /// Add `quantity` (negative to subtract) to the level
+    fn add(&mut self, side: Side, price: Decimal, quantity: Decimal) {
+        let quantity = self.book.quantity(side, &price).unwrap_or_default() + quantity;
+        self.book.apply(
+            side,
+            PriceLevel {
+                price,
+                quantity: quantity.max(Decimal::ZERO),
+            },
+        );
+    }
+
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Level {
+                price,
+                count,
+                amount,
+            } => self.book.apply(
+                Self::side(&amount),
+                PriceLevel {
+                    price,
+                    quantity: if count == 0 {
+                        Decimal::ZERO
+                    } else {
+                        amount.abs()
+                    },
+                },
+            ),
+            Entry::Order { id, price, amount } => {
+                if let Some((side, order)) = self.orders.remove(&id) {
+                    self.add(side, order.price, -order.quantity);
+                }
+
+                if !price.is_zero() {
+                    let side = Self::side(&amount);
+                    let quantity = amount.abs();
+                    self.add(side, price, quantity);
+                    self.orders
+                        .insert(id, (side, PriceLevel { price, quantity }));
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.book.clear();
+        self.orders.clear();
+    }
+}
+
+/// Book of `book` channel, the snapshot followed by updates of levels or orders
+///
+/// The stream ends when bitfinex asks to reconnect or no heartbeat is received for `heartbeat_timeout`
+pub async fn get_book_stream(
+    url: Url,
+    symbol: &str,
+    precision: Precision,
+    length: Length,
+    heartbeat_timeout: Duration,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    info!("Connect to bitfinex by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    ws.send(Message::Text(
+        json!({
+            "event": "subscribe",
+            "channel": "book",
+            "symbol": symbol,
+            "prec": precision.name(),
+            "len": u16::from(length).to_string(),
+        })
+        .to_string(),
+    ))
+    .await?;
+
+    info!("Send subscribe for {symbol}");
+
+    let channel_id = loop {
+        let event = ws.next().await.ok_or(Error::ConnectionClosed)?;
+        match event? {
+            Message::Text(text) => match serde_json::from_str::<Response>(&text)? {
+                Response::Event(Event::Subscribed { channel_id }) => break channel_id,
+                Response::Event(Event::Error) => {
+                    return Err(Error::SubscriptionNotSuccess { response: text })
+                }
+                response => trace!("Skip {response:?}"),
+            },
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => {
+                warn!("Unexpected message {other:?}, expected response to attempt to subscribe");
+                continue;
+            }
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+
+    tokio::spawn(
+        async move {
+            let mut book = BitfinexBook::default();
+
+            loop {
+                let event = match tokio::time::timeout(heartbeat_timeout, ws.next()).await {
+                    Ok(Some(event)) => event,
+                    Ok(None) => return,
+                    Err(_) => {
+                        warn!("No heartbeat for {heartbeat_timeout:?}, close stream");
+                        let _ = sender
+                            .send(Err(Error::HeartbeatTimeout(heartbeat_timeout)))
+                            .await;
+                        return;
+                    }
+                };
+
+                let data = match event {
+                    Ok(Message::Text(text)) => match serde_json::from_str::<'_, Response>(&text) {
+                        Ok(Response::Channel(mut payload))
+                            if payload.len() >= 2 && payload[0].as_u64() == Some(channel_id) =>
+                        {
+                            serde_json::from_value::<ChannelData>(payload.swap_remove(1))
+                        }
+                        Ok(Response::Event(Event::Info {
+                            code: Some(code),
+                            msg,
+                        })) if RECONNECT_CODES.contains(&code) => {
+                            warn!(
+                                "Bitfinex requested reconnect with {code}: {msg:?}, close stream"
+                            );
+                            return;
+                        }
+                        Ok(response) => {
+                            trace!("Skip {response:?}");
+                            continue;
+                        }
+                        Err(error) => Err(error),
+                    },
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+                    Ok(other) => {
+                        warn!("Unexpected message {other:?}");
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Error while handle bitfinex ws: {err:?}");
+                        let _ = sender.send(Err(Error::from(err))).await;
+                        return;
+                    }
+                };
+
+                let applied = match data {
+                    Ok(ChannelData::Heartbeat(heartbeat)) => {
+                        trace!("Receive {heartbeat:?}");
+                        continue;
+                    }
+                    Ok(ChannelData::Snapshot(entries)) => {
+                        book.clear();
+                        entries.into_iter().try_for_each(|entry| {
+                            book.apply(entry.decode(precision)?);
+                            Ok::<_, serde_json::Error>(())
+                        })
+                    }
+                    Ok(ChannelData::Update(entry)) => {
+                        entry.decode(precision).map(|entry| book.apply(entry))
+                    }
+                    Err(error) => Err(error),
+                };
+
+                let order_book = match applied {
+                    Ok(()) => Ok(book.book.to_order_book()),
+                    Err(error) => {
+                        error!("{error:?}");
+                        Err(error.into())
+                    }
+                };
+
+                if sender.send(order_book).await.is_err() {
+                    return;
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+pub struct Bitfinex {
+    pub ws_url: Url,
+    pub precision: Precision,
+    pub length: Length,
+    pub heartbeat_timeout: Duration,
+}
+
+impl Bitfinex {
+    /// Top 25 levels of the most precise aggregated book, heartbeats are sent every 15 seconds
+    pub fn new(ws_url: Url) -> Self {
+        Self {
+            ws_url,
+            precision: Precision::P0,
+            length: Length::_25,
+            heartbeat_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Bitfinex {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_book_stream(
+            self.ws_url.clone(),
+            &symbol(base_currency, quote_currency),
+            self.precision,
+            self.length,
+            self.heartbeat_timeout,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_book_stream(
+            self.ws_url.clone(),
+            &instrument.symbol,
+            self.precision,
+            self.length,
+            self.heartbeat_timeout,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    fn order(id: u64, price: &str, amount: &str) -> Entry {
+        Entry::Order {
+            id,
+            price: Decimal::from_str(price).unwrap(),
+            amount: Decimal::from_str(amount).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_symbol() {
+        assert_eq!(symbol("btc", "usd"), "tBTCUSD");
+        assert_eq!(symbol("btc", "usdt"), "tBTCUST");
+        assert_eq!(symbol("doge", "usd"), "tDOGE:USD");
+    }
+
+    #[test]
+    fn test_decode_entry() {
+        let entry = serde_json::from_str::<RawEntry>("[7254.7, 3, -0.00000001]").unwrap();
+        assert_eq!(
+            entry.decode(Precision::P0).unwrap(),
+            Entry::Level {
+                price: Decimal::from_str("7254.7").unwrap(),
+                count: 3,
+                amount: Decimal::from_str("-0.00000001").unwrap(),
+            }
+        );
+
+        let entry = serde_json::from_str::<RawEntry>("[34006738527, 7254.7, 1.5]").unwrap();
+        assert_eq!(
+            entry.decode(Precision::R0).unwrap(),
+            order(34006738527, "7254.7", "1.5")
+        );
+    }
+
+    #[test]
+    fn test_raw_book() {
+        let mut book = BitfinexBook::default();
+        book.apply(order(1, "100", "1"));
+        book.apply(order(2, "100", "2"));
+        book.apply(order(3, "101", "-1"));
+
+        let order_book = book.book.to_order_book();
+        assert_eq!(order_book.bids, vec![level("100", "3")]);
+        assert_eq!(order_book.asks, vec![level("101", "1")]);
+
+        book.apply(order(1, "100", "0.5"));
+        book.apply(order(2, "0", "1"));
+        assert_eq!(book.book.to_order_book().bids, vec![level("100", "0.5")]);
+
+        // Order moved to another price
+        book.apply(order(1, "99", "0.5"));
+        assert_eq!(book.book.to_order_book().bids, vec![level("99", "0.5")]);
+    }
+
+    #[tokio::test]
+    async fn test_book_stream() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                json!({
+                    "event": "subscribe",
+                    "channel": "book",
+                    "symbol": "tBTCUSD",
+                    "prec": "P0",
+                    "len": "25"
+                })
+            );
+
+            stand_in::send_frames(
+                ws,
+                vec![
+                    json!({ "event": "info", "version": 2, "platform": { "status": 1 } }),
+                    json!({
+                        "event": "subscribed",
+                        "channel": "book",
+                        "chanId": 17,
+                        "symbol": "tBTCUSD",
+                        "prec": "P0",
+                        "freq": "F0",
+                        "len": "25",
+                        "pair": "BTCUSD"
+                    }),
+                    json!([
+                        17,
+                        [
+                            [100.0, 2, 1.5],
+                            [99.5, 1, 2],
+                            [101, 1, -1],
+                            [101.5, 3, -2.25]
+                        ]
+                    ]),
+                    json!([17, "hb"]),
+                    json!([17, [100, 0, 1]]),
+                    json!([18, [1, 1, 1]]),
+                    json!([17, [100.5, 1, -3]]),
+                    json!({
+                        "event": "info",
+                        "code": 20051,
+                        "msg": "Stop/Restart Websocket Server (please reconnect)"
+                    }),
+                ]
+                .iter()
+                .map(serde_json::Value::to_string)
+                .collect(),
+            )
+            .await
+        })
+        .await;
+
+        let source = Bitfinex::new(ws_url);
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.bids,
+            vec![level("100", "1.5"), level("99.5", "2")]
+        );
+        assert_eq!(
+            order_book.asks,
+            vec![level("101", "1"), level("101.5", "2.25")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("99.5", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.asks,
+            vec![
+                level("100.5", "3"),
+                level("101", "1"),
+                level("101.5", "2.25")
+            ]
+        );
+
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_timeout() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            stand_in::send_frames(
+                ws,
+                vec![json!({ "event": "subscribed", "chanId": 17 }).to_string()],
+            )
+            .await
+        })
+        .await;
+
+        let source = Bitfinex {
+            heartbeat_timeout: Duration::from_millis(50),
+            ..Bitfinex::new(ws_url)
+        };
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(Error::HeartbeatTimeout(_)))
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_closed_before_subscription() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+
+        let source = Bitfinex::new(ws_url);
+        assert!(matches!(
+            source.get_order_books_stream("btc", "usd").await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+}
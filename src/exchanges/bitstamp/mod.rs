@@ -0,0 +1,479 @@
+use std::{fmt::Display, str::FromStr};
+
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::{future::Either, sink::SinkExt};
+use serde::{Deserialize, Deserializer};
+use some_to_err::ErrOr;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook},
+};
+
+mod trading_pairs;
+pub use trading_pairs::{PairInfo, TradingPairs};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error("The input URL cannot be a base URL. Please provide a full URL.")]
+    UrlCannotBeBase,
+    #[error("While read cached pairs: {0}")]
+    PairsCache(std::io::Error),
+    #[error("This pair not supported by service")]
+    PairNotSupported {
+        base_currency: String,
+        quote_currency: String,
+    },
+    #[error("Subscription for warrants was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+}
+
+/// Symbol of the pair in channels and urls, e.g. `btcusd`
+pub fn symbol(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}{}",
+        base_currency.to_lowercase(),
+        quote_currency.to_lowercase()
+    )
+}
+
+async fn check_subscription_success(
+    stream: &mut (impl Unpin + Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>>),
+) -> Result<(), Error> {
+    while let Some(event) = stream.next().await {
+        match event {
+            Ok(Message::Text(text)) => {
+                #[derive(Debug, serde::Deserialize)]
+                struct Response {
+                    event: String,
+                    channel: String,
+                }
+
+                serde_json::from_str::<Response>(&text)?
+                    .event
+                    .ne(&"bts:subscription_succeeded")
+                    .then_some(Error::SubscriptionNotSuccess { response: text })
+                    .err_or(())?;
+
+                return Ok(());
+            }
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {
+                continue;
+            }
+            Ok(other) => {
+                warn!("Unexpected message {other:?}, expected response to attempt to subscribe");
+                continue;
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+        };
+    }
+
+    Ok(())
+}
+
+async fn subscribe(
+    url: Url,
+    channel: &str,
+) -> Result<impl Unpin + Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>>, Error>
+{
+    info!("Connect to bitstamp by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    trace!("Bitstamp channel: {channel}");
+    ws.send(Message::Text(format!(
+        r#"{{
+            "event": "bts:subscribe",
+            "data": {{
+                "channel": "{channel}"
+            }}
+        }}"#,
+    )))
+    .await?;
+
+    info!("Send subscribe for {channel}");
+
+    check_subscription_success(&mut ws).await?;
+
+    Ok(ws)
+}
+
+pub async fn get_summary_stream(
+    url: Url,
+    symbol: &str,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let channel = format!("order_book_{symbol}");
+    let ws = subscribe(url, &channel).await?;
+
+    Ok(ws.filter_map(move |event| match event {
+        Ok(Message::Text(text)) => {
+            #[derive(Debug, serde::Deserialize)]
+            struct Response {
+                event: String,
+                channel: String,
+                data: OrderBook,
+            }
+
+            match serde_json::from_str::<'_, Response>(&text) {
+                Ok(response) => {
+                    trace!("Receive {response:?}");
+                    response.channel.eq(&channel).then_some(Ok(response.data))
+                }
+                Err(error) => {
+                    error!("{error:?}");
+                    Some(Err(error.into()))
+                }
+            }
+        }
+        Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => None,
+        Ok(other) => {
+            warn!("Unexpected message {other:?}");
+            None
+        }
+        Err(err) => {
+            error!("Error while handle bitstamp ws: {err:?}");
+            Some(Err(Error::from(err)))
+        }
+    }))
+}
+
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Order book with the time it was formed, both REST snapshot and `diff_order_book` data
+#[derive(Debug, Deserialize)]
+struct TimedOrderBook {
+    #[serde(deserialize_with = "deserialize_from_str")]
+    microtimestamp: u64,
+    #[serde(flatten)]
+    order_book: OrderBook,
+}
+
+async fn fetch_snapshot(rest_url: &Url, symbol: &str) -> Result<TimedOrderBook, Error> {
+    let url = rest_url.join(&format!("api/v2/order_book/{symbol}/"))?;
+
+    debug!("Fetch bitstamp snapshot by {url}");
+
+    Ok(reqwest::get(url)
+        .await?
+        .error_for_status()?
+        .json::<TimedOrderBook>()
+        .await?)
+}
+
+/// Full depth book maintained from the REST snapshot and `diff_order_book` deltas
+///
+/// Deltas are buffered by the websocket while the snapshot is fetched,
+/// those not newer than the last applied `microtimestamp` are dropped
+pub async fn get_diff_order_book_stream(
+    ws_url: Url,
+    rest_url: Url,
+    symbol: &str,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let channel = format!("diff_order_book_{symbol}");
+    let mut ws = subscribe(ws_url, &channel).await?;
+
+    let snapshot = fetch_snapshot(&rest_url, symbol).await?;
+    let mut last_microtimestamp = snapshot.microtimestamp;
+    let mut book = LocalOrderBook::from(snapshot.order_book);
+
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+    sender
+        .send(Ok(book.to_order_book()))
+        .await
+        .expect("Receiver can't be dropped yet");
+
+    tokio::spawn(
+        async move {
+            while let Some(event) = ws.next().await {
+                let order_book = match event {
+                    Ok(Message::Text(text)) => {
+                        #[derive(Debug, Deserialize)]
+                        struct Response {
+                            event: String,
+                            channel: String,
+                            data: serde_json::Value,
+                        }
+
+                        match serde_json::from_str::<'_, Response>(&text) {
+                            Ok(response) if response.event == "bts:request_reconnect" => {
+                                warn!("Bitstamp requested reconnect, close stream");
+                                return;
+                            }
+                            Ok(response)
+                                if response.event == "data" && response.channel == channel =>
+                            {
+                                match serde_json::from_value::<TimedOrderBook>(response.data) {
+                                    Ok(delta) if delta.microtimestamp <= last_microtimestamp => {
+                                        trace!("Drop outdated delta {delta:?}");
+                                        continue;
+                                    }
+                                    Ok(delta) => {
+                                        last_microtimestamp = delta.microtimestamp;
+                                        book.apply_order_book(delta.order_book);
+                                        Ok(book.to_order_book())
+                                    }
+                                    Err(error) => {
+                                        error!("{error:?}");
+                                        Err(error.into())
+                                    }
+                                }
+                            }
+                            Ok(response) => {
+                                trace!("Skip {response:?}");
+                                continue;
+                            }
+                            Err(error) => {
+                                error!("{error:?}");
+                                Err(error.into())
+                            }
+                        }
+                    }
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+                    Ok(other) => {
+                        warn!("Unexpected message {other:?}");
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Error while handle bitstamp ws: {err:?}");
+                        let _ = sender.send(Err(Error::from(err))).await;
+                        return;
+                    }
+                };
+
+                if sender.send(order_book).await.is_err() {
+                    return;
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Channel {
+    /// `order_book_{pair}`, snapshots of the top 100 levels
+    OrderBook,
+    /// `diff_order_book_{pair}` over REST `order_book` snapshot, full depth
+    DiffOrderBook { rest_url: Url },
+}
+
+pub struct Bitstamp {
+    ws_url: Url,
+    channel: Channel,
+    /// Pairs are checked before subscription if set, otherwise bitstamp decides
+    trading_pairs: Option<TradingPairs>,
+}
+impl Default for Bitstamp {
+    fn default() -> Self {
+        Self {
+            ws_url: "wss://ws.bitstamp.net/".parse().unwrap(),
+            channel: Channel::OrderBook,
+            trading_pairs: None,
+        }
+    }
+}
+
+impl Bitstamp {
+    pub fn new(ws_url: Url) -> Self {
+        Self {
+            ws_url,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_channel(self, channel: Channel) -> Self {
+        Self { channel, ..self }
+    }
+
+    pub fn with_trading_pairs(self, trading_pairs: TradingPairs) -> Self {
+        Self {
+            trading_pairs: Some(trading_pairs),
+            ..self
+        }
+    }
+
+    /// Tick size and minimum order of the pair, if trading pairs are set and it's listed
+    pub fn pair_info(&self, base_currency: &str, quote_currency: &str) -> Option<PairInfo> {
+        self.trading_pairs
+            .as_ref()?
+            .get(base_currency, quote_currency)
+    }
+
+    fn check_pair(&self, base_currency: &str, quote_currency: &str) -> Result<(), Error> {
+        self.trading_pairs
+            .as_ref()
+            .filter(|trading_pairs| trading_pairs.get(base_currency, quote_currency).is_none())
+            .map(|_| Error::PairNotSupported {
+                base_currency: base_currency.to_lowercase(),
+                quote_currency: quote_currency.to_lowercase(),
+            })
+            .err_or(())
+    }
+
+    async fn get_channel_stream(
+        &self,
+        symbol: &str,
+    ) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+        Ok(match &self.channel {
+            Channel::OrderBook => {
+                Either::Left(get_summary_stream(self.ws_url.clone(), symbol).await?)
+            }
+            Channel::DiffOrderBook { rest_url } => Either::Right(
+                get_diff_order_book_stream(self.ws_url.clone(), rest_url.clone(), symbol).await?,
+            ),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Bitstamp {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        self.check_pair(base_currency, quote_currency)?;
+
+        self.get_channel_stream(&symbol(base_currency, quote_currency))
+            .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        self.check_pair(&instrument.base_currency, &instrument.quote_currency)?;
+
+        self.get_channel_stream(&instrument.symbol).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    fn timed_order_book(
+        microtimestamp: u64,
+        bids: &[[&str; 2]],
+        asks: &[[&str; 2]],
+    ) -> serde_json::Value {
+        json!({
+            "timestamp": (microtimestamp / 1_000_000).to_string(),
+            "microtimestamp": microtimestamp.to_string(),
+            "bids": bids,
+            "asks": asks,
+        })
+    }
+
+    fn delta(microtimestamp: u64, bids: &[[&str; 2]], asks: &[[&str; 2]]) -> String {
+        json!({
+            "event": "data",
+            "channel": "diff_order_book_btcusd",
+            "data": timed_order_book(microtimestamp, bids, asks),
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_diff_order_book_stream() {
+        let (rest_url, requests) = stand_in::serve_http(vec![timed_order_book(
+            1_000,
+            &[["100", "1"], ["99", "2"]],
+            &[["101", "1"], ["102", "2"]],
+        )
+        .to_string()])
+        .await;
+
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                json!({
+                    "event": "bts:subscribe",
+                    "data": { "channel": "diff_order_book_btcusd" }
+                })
+            );
+
+            stand_in::send_frames(
+                ws,
+                vec![
+                    json!({
+                        "event": "bts:subscription_succeeded",
+                        "channel": "diff_order_book_btcusd",
+                        "data": {}
+                    })
+                    .to_string(),
+                    // Already included into the snapshot
+                    delta(900, &[["100", "5"]], &[]),
+                    delta(1_100, &[["100", "3"], ["98", "1"]], &[["101", "0"]]),
+                    delta(1_200, &[["99", "0"]], &[["103", "4"]]),
+                ],
+            )
+            .await
+        })
+        .await;
+
+        let source = Bitstamp::new(ws_url).with_channel(Channel::DiffOrderBook { rest_url });
+        let mut stream = source.get_order_books_stream("BTC", "USD").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1"), level("99", "2")]);
+        assert_eq!(order_book.asks, vec![level("101", "1"), level("102", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.bids,
+            vec![level("100", "3"), level("99", "2"), level("98", "1")]
+        );
+        assert_eq!(order_book.asks, vec![level("102", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "3"), level("98", "1")]);
+        assert_eq!(order_book.asks, vec![level("102", "2"), level("103", "4")]);
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["/api/v2/order_book/btcusd/"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pair_not_supported() {
+        let source = Bitstamp::default().with_trading_pairs(TradingPairs::default());
+        assert!(matches!(
+            source.get_order_books_stream("btc", "usd").await,
+            Err(Error::PairNotSupported { .. })
+        ));
+    }
+}
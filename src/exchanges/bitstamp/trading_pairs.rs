@@ -0,0 +1,331 @@
+//! Pairs listed by bitstamp, loaded from `trading-pairs-info` instead of a hard-coded list
+//!
+//! The last successful response is cached to a file, which is used when bitstamp
+//! is not reachable at startup. Each loaded listing replaces the `bitstamp` listing of the
+//! instruments registry, which checks pairs before subscription.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use tokio::task::JoinHandle;
+use tracing::*;
+use url::Url;
+
+use super::Error;
+use crate::{
+    instruments::{Instrument, Registry, Status},
+    order_book::Pair,
+};
+
+/// Name of the exchange in the instruments registry
+const EXCHANGE_NAME: &str = "bitstamp";
+
+/// Pair as listed by `trading-pairs-info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairInfo {
+    /// Symbol of the pair in urls and channels, e.g. `btcusd`
+    pub url_symbol: String,
+    /// Pair by its name, e.g. `BTC/USD`
+    pub pair: Pair,
+    /// Minimal price step, in the quote currency
+    pub tick_size: Decimal,
+    /// Minimal quantity step, in the base currency
+    pub lot_size: Decimal,
+    /// Minimal order value, in the quote currency
+    pub minimum_order: Decimal,
+    /// Delisted and suspended pairs are listed with disabled trading
+    pub trading_enabled: bool,
+}
+
+impl<'de> Deserialize<'de> for PairInfo {
+    fn deserialize<D>(deserializer: D) -> Result<PairInfo, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize)]
+        struct Raw {
+            name: String,
+            url_symbol: String,
+            base_decimals: u32,
+            counter_decimals: u32,
+            /// Value with currency, e.g. `10.0 USD`
+            minimum_order: String,
+            trading: String,
+        }
+
+        let raw = Raw::deserialize(deserializer)?;
+        let minimum_order = raw
+            .minimum_order
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| D::Error::custom("Empty minimum_order"))?;
+
+        Ok(PairInfo {
+            url_symbol: raw.url_symbol,
+            pair: Pair::from_str(&raw.name).map_err(D::Error::custom)?,
+            tick_size: Decimal::new(1, raw.counter_decimals),
+            lot_size: Decimal::new(1, raw.base_decimals),
+            minimum_order: Decimal::from_str(minimum_order).map_err(D::Error::custom)?,
+            trading_enabled: raw.trading == "Enabled",
+        })
+    }
+}
+
+impl PairInfo {
+    /// Instrument of the registry, pairs with disabled trading are halted
+    pub fn to_instrument(&self) -> Instrument {
+        Instrument {
+            symbol: self.url_symbol.clone(),
+            base_currency: self.pair.base_currency.clone(),
+            quote_currency: self.pair.quote_currency.clone(),
+            tick_size: Some(self.tick_size),
+            lot_size: Some(self.lot_size),
+            status: if self.trading_enabled {
+                Status::Trading
+            } else {
+                Status::Halted
+            },
+        }
+    }
+}
+
+/// Pairs by url symbol, shared by all clones and replaced on refresh
+#[derive(Debug, Clone, Default)]
+pub struct TradingPairs {
+    pairs: Arc<RwLock<HashMap<String, PairInfo>>>,
+    /// Registry the listing is inserted to on every refresh
+    registry: Registry,
+}
+
+impl TradingPairs {
+    /// Load pairs from `rest_url`, from `cache_path` if the request fails, and register them
+    pub async fn load(
+        registry: Registry,
+        rest_url: &Url,
+        cache_path: Option<&Path>,
+    ) -> Result<Self, Error> {
+        let trading_pairs = Self {
+            pairs: Arc::default(),
+            registry,
+        };
+        trading_pairs.refresh(rest_url, cache_path).await?;
+        Ok(trading_pairs)
+    }
+
+    /// Replace pairs by the ones currently listed, keep the previous ones if the request fails
+    /// and there is no cache to fall back to
+    pub async fn refresh(&self, rest_url: &Url, cache_path: Option<&Path>) -> Result<(), Error> {
+        let pairs = match fetch(rest_url).await {
+            Ok((body, pairs)) => {
+                if let Some(cache_path) = cache_path {
+                    if let Err(err) = tokio::fs::write(cache_path, body).await {
+                        warn!("Error while cache bitstamp pairs to {cache_path:?}: {err:?}");
+                    }
+                }
+                pairs
+            }
+            Err(err) => match cache_path {
+                Some(cache_path) => {
+                    warn!("Error while fetch bitstamp pairs, load cached ones: {err:?}");
+                    read_cache(cache_path).await?
+                }
+                None => return Err(err),
+            },
+        };
+
+        info!("Loaded {} bitstamp pairs", pairs.len());
+
+        self.registry.insert(
+            EXCHANGE_NAME.to_owned(),
+            pairs
+                .iter()
+                .map(|pair| (pair.pair.clone(), pair.to_instrument())),
+        );
+        *self.pairs.write().unwrap() = pairs
+            .into_iter()
+            .map(|pair| (pair.url_symbol.clone(), pair))
+            .collect();
+
+        Ok(())
+    }
+
+    /// Refresh pairs every `period`, errors are logged and the previous pairs are kept
+    pub fn spawn_refresh(
+        &self,
+        rest_url: Url,
+        cache_path: Option<PathBuf>,
+        period: Duration,
+    ) -> JoinHandle<()> {
+        let trading_pairs = self.clone();
+        tokio::spawn(
+            async move {
+                let mut interval =
+                    tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = trading_pairs
+                        .refresh(&rest_url, cache_path.as_deref())
+                        .await
+                    {
+                        error!("Error while refresh bitstamp pairs: {err:?}");
+                    }
+                }
+            }
+            .instrument(Span::current()),
+        )
+    }
+
+    /// Pair available for trading
+    pub fn get(&self, base_currency: &str, quote_currency: &str) -> Option<PairInfo> {
+        self.pairs
+            .read()
+            .unwrap()
+            .get(&format!(
+                "{}{}",
+                base_currency.to_lowercase(),
+                quote_currency.to_lowercase()
+            ))
+            .filter(|pair| pair.trading_enabled)
+            .cloned()
+    }
+}
+
+/// Listed pairs with the body of the response, to be cached as is
+async fn fetch(rest_url: &Url) -> Result<(String, Vec<PairInfo>), Error> {
+    let url = rest_url.join("api/v2/trading-pairs-info/")?;
+
+    debug!("Fetch bitstamp pairs by {url}");
+
+    let body = reqwest::get(url).await?.error_for_status()?.text().await?;
+    let pairs = serde_json::from_str(&body)?;
+    Ok((body, pairs))
+}
+
+async fn read_cache(cache_path: &Path) -> Result<Vec<PairInfo>, Error> {
+    let body = tokio::fs::read_to_string(cache_path)
+        .await
+        .map_err(Error::PairsCache)?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{exchanges::stand_in, instruments};
+
+    fn trading_pairs_info() -> String {
+        json!([
+            {
+                "name": "BTC/USD",
+                "url_symbol": "btcusd",
+                "base_decimals": 8,
+                "counter_decimals": 0,
+                "instant_order_counter_decimals": 2,
+                "minimum_order": "10 USD",
+                "trading": "Enabled",
+                "instant_and_market_orders": "Enabled",
+                "description": "Bitcoin / U.S. dollar"
+            },
+            {
+                "name": "ETH/BTC",
+                "url_symbol": "ethbtc",
+                "base_decimals": 8,
+                "counter_decimals": 8,
+                "instant_order_counter_decimals": 8,
+                "minimum_order": "0.00020000 BTC",
+                "trading": "Enabled",
+                "instant_and_market_orders": "Enabled",
+                "description": "Ether / Bitcoin"
+            },
+            {
+                "name": "OMG/USD",
+                "url_symbol": "omgusd",
+                "base_decimals": 8,
+                "counter_decimals": 5,
+                "instant_order_counter_decimals": 5,
+                "minimum_order": "10.0 USD",
+                "trading": "Disabled",
+                "instant_and_market_orders": "Disabled",
+                "description": "OMG Network / U.S. dollar"
+            }
+        ])
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_load_and_fall_back_to_cache() {
+        let cache_path =
+            std::env::temp_dir().join(format!("bitstamp-pairs-{}.json", rand::random::<u64>()));
+
+        let registry = Registry::default();
+        let (rest_url, requests) = stand_in::serve_http(vec![trading_pairs_info()]).await;
+        let trading_pairs = TradingPairs::load(registry.clone(), &rest_url, Some(&cache_path))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            trading_pairs.get("BTC", "USD"),
+            Some(PairInfo {
+                url_symbol: "btcusd".to_owned(),
+                pair: Pair::new("btc", "usd"),
+                tick_size: Decimal::ONE,
+                lot_size: Decimal::from_str("0.00000001").unwrap(),
+                minimum_order: Decimal::from(10),
+                trading_enabled: true,
+            })
+        );
+        let eth_btc = trading_pairs.get("eth", "btc").unwrap();
+        assert_eq!(eth_btc.tick_size, Decimal::from_str("0.00000001").unwrap());
+        assert_eq!(eth_btc.minimum_order, Decimal::from_str("0.0002").unwrap());
+        assert_eq!(trading_pairs.get("omg", "usd"), None);
+        assert_eq!(trading_pairs.get("abc", "usd"), None);
+
+        assert_eq!(
+            registry
+                .instrument("bitstamp", &Pair::new("xbt", "usd"))
+                .unwrap()
+                .map(|instrument| (instrument.symbol, instrument.tick_size)),
+            Some(("btcusd".to_owned(), Some(Decimal::ONE)))
+        );
+        assert!(matches!(
+            registry.instrument("bitstamp", &Pair::new("omg", "usd")),
+            Err(instruments::Error::NotTrading {
+                status: Status::Halted,
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry.instrument("bitstamp", &Pair::new("abc", "usd")),
+            Err(instruments::Error::NotListed { .. })
+        ));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["/api/v2/trading-pairs-info/"]
+        );
+
+        // Bitstamp responds with something unexpected, the cached pairs are loaded
+        let (rest_url, _) = stand_in::serve_http(vec!["<html></html>".to_owned()]).await;
+        let trading_pairs = TradingPairs::load(Registry::default(), &rest_url, Some(&cache_path))
+            .await
+            .unwrap();
+        assert!(trading_pairs.get("btc", "usd").is_some());
+
+        assert!(matches!(
+            TradingPairs::load(Registry::default(), &rest_url, None).await,
+            Err(Error::Format(_))
+        ));
+
+        std::fs::remove_file(cache_path).unwrap();
+    }
+}
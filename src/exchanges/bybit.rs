@@ -0,0 +1,396 @@
+use std::{str::FromStr, time::Duration};
+
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::sink::SinkExt;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+    #[error("Connection is closed before the subscription is confirmed")]
+    ConnectionClosed,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Depth {
+    _1,
+    _50,
+    _200,
+}
+impl From<Depth> for usize {
+    fn from(value: Depth) -> Self {
+        match value {
+            Depth::_1 => 1,
+            Depth::_50 => 50,
+            Depth::_200 => 200,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Bybit depth {0:?} must be one of 1, 50 or 200")]
+pub struct ParseDepthError(String);
+
+impl FromStr for Depth {
+    type Err = ParseDepthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "1" => Ok(Depth::_1),
+            "50" => Ok(Depth::_50),
+            "200" => Ok(Depth::_200),
+            _ => Err(ParseDepthError(s.to_owned())),
+        }
+    }
+}
+
+/// Bybit symbol of the pair, e.g. `BTCUSDT`
+pub fn symbol(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}{}",
+        base_currency.to_uppercase(),
+        quote_currency.to_uppercase()
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct BookData {
+    #[serde(rename = "b")]
+    bids: Vec<PriceLevel>,
+    #[serde(rename = "a")]
+    asks: Vec<PriceLevel>,
+    /// Update id, `1` means the service was restarted and the data is a snapshot
+    #[serde(rename = "u")]
+    update_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DataType {
+    Snapshot,
+    Delta,
+}
+
+#[derive(Debug, Deserialize)]
+struct Push {
+    topic: String,
+    #[serde(rename = "type")]
+    data_type: DataType,
+    data: BookData,
+}
+
+/// Response to `subscribe` and `ping` operations
+#[derive(Debug, Deserialize)]
+struct OperationResponse {
+    op: String,
+    #[serde(default)]
+    success: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Response {
+    Push(Push),
+    Operation(OperationResponse),
+}
+
+/// Book kept by update ids, stale deltas are dropped
+#[derive(Debug, Default)]
+struct BybitBook {
+    book: LocalOrderBook,
+    update_id: Option<u64>,
+}
+impl BybitBook {
+    /// Returns `false` if the data is not newer than the book
+    fn apply(&mut self, data_type: DataType, data: BookData, depth: usize) -> bool {
+        let reset = matches!(data_type, DataType::Snapshot) || data.update_id == 1;
+
+        match self.update_id {
+            None if !reset => {
+                trace!("Skip delta received before snapshot");
+                return false;
+            }
+            Some(update_id) if !reset && data.update_id <= update_id => {
+                trace!("Skip stale delta {}", data.update_id);
+                return false;
+            }
+            _ => {}
+        }
+
+        if reset {
+            self.book.clear();
+        }
+        self.book.apply_order_book(OrderBook {
+            bids: data.bids,
+            asks: data.asks,
+        });
+        self.book.truncate(depth);
+        self.update_id = Some(data.update_id);
+
+        true
+    }
+}
+
+/// Book of `orderbook.{depth}.{symbol}` topic, snapshot followed by deltas
+///
+/// `ping` is sent every `ping_interval`, since bybit drops connections silent for a while
+pub async fn get_orderbook_stream(
+    url: Url,
+    symbol: &str,
+    depth: Depth,
+    ping_interval: Duration,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let depth = usize::from(depth);
+    let topic = format!("orderbook.{depth}.{symbol}");
+
+    info!("Connect to bybit by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    ws.send(Message::Text(
+        json!({ "op": "subscribe", "args": [topic] }).to_string(),
+    ))
+    .await?;
+
+    info!("Send subscribe for {topic}");
+
+    loop {
+        let event = ws.next().await.ok_or(Error::ConnectionClosed)?;
+        match event? {
+            Message::Text(text) => match serde_json::from_str::<Response>(&text)? {
+                Response::Operation(OperationResponse { op, success }) if op == "subscribe" => {
+                    match success {
+                        Some(true) => break,
+                        _ => return Err(Error::SubscriptionNotSuccess { response: text }),
+                    }
+                }
+                response => trace!("Skip {response:?}"),
+            },
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => {
+                warn!("Unexpected message {other:?}, expected response to attempt to subscribe");
+                continue;
+            }
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+
+    tokio::spawn(
+        async move {
+            let mut book = BybitBook::default();
+            let mut ping = tokio::time::interval(ping_interval);
+            ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                let event = tokio::select! {
+                    _ = ping.tick() => {
+                        trace!("Send ping");
+                        let ping_message = Message::Text(json!({ "op": "ping" }).to_string());
+                        if let Err(err) = ws.send(ping_message).await {
+                            error!("Error while ping bybit: {err:?}");
+                            let _ = sender.send(Err(Error::from(err))).await;
+                            return;
+                        }
+                        continue;
+                    }
+                    event = ws.next() => match event {
+                        Some(event) => event,
+                        None => return,
+                    },
+                };
+
+                let order_book = match event {
+                    Ok(Message::Text(text)) => match serde_json::from_str::<'_, Response>(&text) {
+                        Ok(Response::Push(push)) if push.topic == topic => {
+                            if !book.apply(push.data_type, push.data, depth) {
+                                continue;
+                            }
+                            Ok(book.book.to_order_book())
+                        }
+                        Ok(response) => {
+                            trace!("Skip {response:?}");
+                            continue;
+                        }
+                        Err(error) => {
+                            error!("{error:?}");
+                            Err(error.into())
+                        }
+                    },
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+                    Ok(other) => {
+                        warn!("Unexpected message {other:?}");
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Error while handle bybit ws: {err:?}");
+                        let _ = sender.send(Err(Error::from(err))).await;
+                        return;
+                    }
+                };
+
+                if sender.send(order_book).await.is_err() {
+                    return;
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+pub struct Bybit {
+    pub ws_url: Url,
+    pub depth: Depth,
+    pub ping_interval: Duration,
+}
+
+impl Bybit {
+    /// Top 50 levels with the ping interval recommended by bybit
+    pub fn new(ws_url: Url) -> Self {
+        Self {
+            ws_url,
+            depth: Depth::_50,
+            ping_interval: Duration::from_secs(20),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Bybit {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_orderbook_stream(
+            self.ws_url.clone(),
+            &symbol(base_currency, quote_currency),
+            self.depth,
+            self.ping_interval,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_orderbook_stream(
+            self.ws_url.clone(),
+            &instrument.symbol,
+            self.depth,
+            self.ping_interval,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    fn push(data_type: &str, update_id: u64, bids: &[[&str; 2]], asks: &[[&str; 2]]) -> String {
+        json!({
+            "topic": "orderbook.50.BTCUSDT",
+            "type": data_type,
+            "ts": 1672304484978u64,
+            "data": { "s": "BTCUSDT", "b": bids, "a": asks, "u": update_id, "seq": 7961638724u64 },
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_orderbook_stream() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                json!({ "op": "subscribe", "args": ["orderbook.50.BTCUSDT"] })
+            );
+
+            ws.send(Message::Text(
+                json!({ "success": true, "ret_msg": "subscribe", "conn_id": "1", "op": "subscribe" })
+                    .to_string(),
+            ))
+            .await
+            .unwrap();
+
+            // Nothing is sent until the client pings
+            let ping = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&ping).unwrap(),
+                json!({ "op": "ping" })
+            );
+
+            stand_in::send_frames(
+                ws,
+                vec![
+                    json!({ "op": "pong", "args": ["1675418560633"], "conn_id": "1" }).to_string(),
+                    push("delta", 9, &[["99", "1"]], &[]),
+                    push("snapshot", 10, &[["100", "1"], ["99", "2"]], &[["101", "1"]]),
+                    push("delta", 10, &[["98", "1"]], &[]),
+                    push("delta", 11, &[["100", "0"]], &[["102", "2"]]),
+                    // Service restart
+                    push("delta", 1, &[["97", "1"]], &[["103", "1"]]),
+                ],
+            )
+            .await
+        })
+        .await;
+
+        let source = Bybit {
+            ping_interval: Duration::from_millis(50),
+            ..Bybit::new(ws_url)
+        };
+        let mut stream = source.get_order_books_stream("btc", "usdt").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1"), level("99", "2")]);
+        assert_eq!(order_book.asks, vec![level("101", "1")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("99", "2")]);
+        assert_eq!(order_book.asks, vec![level("101", "1"), level("102", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1")]);
+        assert_eq!(order_book.asks, vec![level("103", "1")]);
+    }
+
+    #[tokio::test]
+    async fn test_closed_before_subscription() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+
+        let source = Bybit::new(ws_url);
+        assert!(matches!(
+            source.get_order_books_stream("btc", "usdt").await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+}
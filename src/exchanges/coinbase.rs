@@ -0,0 +1,330 @@
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::sink::SinkExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use tokio_stream::{Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel, Side},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+    #[error("Connection is closed before the subscription is confirmed")]
+    ConnectionClosed,
+    #[error("Coinbase error: {message}, {reason}")]
+    Exchange { message: String, reason: String },
+}
+
+/// Coinbase product id of the pair, e.g. `BTC-USD`
+pub fn product_id(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}-{}",
+        base_currency.to_uppercase(),
+        quote_currency.to_uppercase()
+    )
+}
+
+/// `[side, price, size]` entry of `l2update`, zero size removes the level
+#[derive(Debug, PartialEq, Eq)]
+struct Change {
+    side: Side,
+    level: PriceLevel,
+}
+impl<'de> Deserialize<'de> for Change {
+    fn deserialize<D>(deserializer: D) -> Result<Change, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (side, price, quantity): (String, String, String) =
+            Deserialize::deserialize(deserializer)?;
+
+        Ok(Change {
+            side: match side.as_str() {
+                "buy" => Side::Bid,
+                "sell" => Side::Ask,
+                other => return Err(serde::de::Error::custom(format!("Unknown side {other:?}"))),
+            },
+            level: PriceLevel {
+                price: Decimal::from_str_exact(&price).map_err(serde::de::Error::custom)?,
+                quantity: Decimal::from_str_exact(&quantity).map_err(serde::de::Error::custom)?,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Subscriptions,
+    Error {
+        message: String,
+        #[serde(default)]
+        reason: String,
+    },
+    Snapshot {
+        product_id: String,
+        bids: Vec<PriceLevel>,
+        asks: Vec<PriceLevel>,
+    },
+    #[serde(rename = "l2update")]
+    L2Update {
+        product_id: String,
+        changes: Vec<Change>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+async fn subscribe(
+    url: Url,
+    product_id: &str,
+) -> Result<impl Unpin + Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>>, Error>
+{
+    info!("Connect to coinbase by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    ws.send(Message::Text(
+        json!({
+            "type": "subscribe",
+            "product_ids": [product_id],
+            "channels": ["level2"],
+        })
+        .to_string(),
+    ))
+    .await?;
+
+    info!("Send subscribe for {product_id}");
+
+    while let Some(event) = ws.next().await {
+        match event? {
+            Message::Text(text) => match serde_json::from_str::<Response>(&text)? {
+                Response::Subscriptions => return Ok(ws),
+                _ => return Err(Error::SubscriptionNotSuccess { response: text }),
+            },
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => {
+                warn!("Unexpected message {other:?}, expected response to attempt to subscribe");
+                continue;
+            }
+        }
+    }
+
+    Err(Error::ConnectionClosed)
+}
+
+/// Book maintained from `level2` channel, `snapshot` followed by `l2update` changes
+///
+/// Only top `depth` levels per side are emitted after each message
+pub async fn get_level2_stream(
+    url: Url,
+    product_id: &str,
+    depth: usize,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let product_id = product_id.to_owned();
+    let ws = subscribe(url, &product_id).await?;
+    let mut book = None::<LocalOrderBook>;
+
+    Ok(ws.filter_map(move |event| match event {
+        Ok(Message::Text(text)) => match serde_json::from_str::<'_, Response>(&text) {
+            Ok(Response::Snapshot {
+                product_id: snapshot_product_id,
+                bids,
+                asks,
+            }) if snapshot_product_id == product_id => {
+                let snapshot = LocalOrderBook::from(OrderBook { bids, asks });
+                let order_book = snapshot.top(depth);
+                book = Some(snapshot);
+                Some(Ok(order_book))
+            }
+            Ok(Response::L2Update {
+                product_id: update_product_id,
+                changes,
+            }) if update_product_id == product_id => match book.as_mut() {
+                Some(book) => {
+                    changes
+                        .into_iter()
+                        .for_each(|Change { side, level }| book.apply(side, level));
+                    Some(Ok(book.top(depth)))
+                }
+                None => {
+                    warn!("Skip update received before snapshot");
+                    None
+                }
+            },
+            Ok(Response::Error { message, reason }) => {
+                error!("Coinbase error: {message}, {reason}");
+                Some(Err(Error::Exchange { message, reason }))
+            }
+            Ok(response) => {
+                trace!("Skip {response:?}");
+                None
+            }
+            Err(error) => {
+                error!("{error:?}");
+                Some(Err(error.into()))
+            }
+        },
+        Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => None,
+        Ok(other) => {
+            warn!("Unexpected message {other:?}");
+            None
+        }
+        Err(err) => {
+            error!("Error while handle coinbase ws: {err:?}");
+            Some(Err(Error::from(err)))
+        }
+    }))
+}
+
+pub struct Coinbase {
+    pub ws_url: Url,
+    /// Levels per side emitted from the local book
+    pub depth: usize,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Coinbase {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_level2_stream(
+            self.ws_url.clone(),
+            &product_id(base_currency, quote_currency),
+            self.depth,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_level2_stream(self.ws_url.clone(), &instrument.symbol, self.depth).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    #[test]
+    fn test_product_id() {
+        assert_eq!(product_id("btc", "usd"), "BTC-USD");
+    }
+
+    #[tokio::test]
+    async fn test_level2_stream() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                json!({
+                    "type": "subscribe",
+                    "product_ids": ["BTC-USD"],
+                    "channels": ["level2"]
+                })
+            );
+
+            stand_in::send_frames(
+                ws,
+                vec![
+                    r#"{"type":"subscriptions","channels":[{"name":"level2","product_ids":["BTC-USD"]}]}"#,
+                    r#"{"type":"snapshot","product_id":"BTC-USD","bids":[["100.00","1.5"],["99.50","2"],["99.00","3"]],"asks":[["101.00","1"],["101.50","2"]]}"#,
+                    r#"{"type":"l2update","product_id":"ETH-USD","time":"2023-03-20T10:00:00.000000Z","changes":[["buy","1.00","1"]]}"#,
+                    r#"{"type":"l2update","product_id":"BTC-USD","time":"2023-03-20T10:00:00.100000Z","changes":[["buy","100.00","0.00000000"],["sell","100.50","4"]]}"#,
+                    r#"{"type":"l2update","product_id":"BTC-USD","time":"2023-03-20T10:00:00.200000Z","changes":[["buy","99.75","1"]]}"#,
+                ]
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            )
+            .await
+        })
+        .await;
+
+        let source = Coinbase { ws_url, depth: 2 };
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.bids,
+            vec![level("100", "1.5"), level("99.5", "2")]
+        );
+        assert_eq!(
+            order_book.asks,
+            vec![level("101", "1"), level("101.5", "2")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("99.5", "2"), level("99", "3")]);
+        assert_eq!(
+            order_book.asks,
+            vec![level("100.5", "4"), level("101", "1")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.bids,
+            vec![level("99.75", "1"), level("99.5", "2")]
+        );
+        assert_eq!(
+            order_book.asks,
+            vec![level("100.5", "4"), level("101", "1")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscription_error() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            stand_in::send_frames(
+                ws,
+                vec![
+                    r#"{"type":"error","message":"Failed to subscribe","reason":"ABC-DEF is not a valid product"}"#
+                        .to_owned(),
+                ],
+            )
+            .await
+        })
+        .await;
+
+        let source = Coinbase { ws_url, depth: 2 };
+        assert!(matches!(
+            source.get_order_books_stream("abc", "def").await,
+            Err(Error::SubscriptionNotSuccess { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_closed_before_subscription() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+
+        let source = Coinbase { ws_url, depth: 2 };
+        assert!(matches!(
+            source.get_order_books_stream("btc", "usd").await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+//! Decompression of binary websocket frames, for venues sending compressed JSON
+
+use std::io::{self, Read};
+
+use async_tungstenite::tungstenite::Message;
+use flate2::read::{DeflateDecoder, GzDecoder};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    /// Raw deflate stream without zlib or gzip headers
+    Deflate,
+}
+
+impl Compression {
+    /// Decompress `data` into UTF-8 text
+    pub fn decompress(&self, data: &[u8]) -> io::Result<String> {
+        let mut text = String::new();
+        match self {
+            Compression::Gzip => GzDecoder::new(data).read_to_string(&mut text)?,
+            Compression::Deflate => DeflateDecoder::new(data).read_to_string(&mut text)?,
+        };
+        Ok(text)
+    }
+
+    /// Text of a data frame, binary frames are decompressed, `None` for control frames
+    pub fn text(&self, message: &Message) -> Option<io::Result<String>> {
+        match message {
+            Message::Text(text) => Some(Ok(text.clone())),
+            Message::Binary(data) => Some(self.decompress(data)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::{DeflateEncoder, GzEncoder};
+
+    use super::*;
+
+    const TEXT: &str = r#"{"ping":1492420473027}"#;
+
+    #[test]
+    fn test_decompress() {
+        let mut gzip = GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(TEXT.as_bytes()).unwrap();
+        assert_eq!(
+            Compression::Gzip
+                .decompress(&gzip.finish().unwrap())
+                .unwrap(),
+            TEXT
+        );
+
+        let mut deflate = DeflateEncoder::new(vec![], flate2::Compression::default());
+        deflate.write_all(TEXT.as_bytes()).unwrap();
+        assert_eq!(
+            Compression::Deflate
+                .decompress(&deflate.finish().unwrap())
+                .unwrap(),
+            TEXT
+        );
+
+        assert!(Compression::Gzip.decompress(TEXT.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_text() {
+        let mut gzip = GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(TEXT.as_bytes()).unwrap();
+        let binary = Message::Binary(gzip.finish().unwrap());
+
+        assert_eq!(Compression::Gzip.text(&binary).unwrap().unwrap(), TEXT);
+        assert_eq!(
+            Compression::Gzip
+                .text(&Message::Text(TEXT.to_owned()))
+                .unwrap()
+                .unwrap(),
+            TEXT
+        );
+        assert!(Compression::Gzip.text(&Message::Ping(vec![])).is_none());
+    }
+}
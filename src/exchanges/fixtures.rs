@@ -0,0 +1,14 @@
+//! Values shared by tests of connectors
+
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+
+use crate::order_book::PriceLevel;
+
+pub fn level(price: &str, quantity: &str) -> PriceLevel {
+    PriceLevel {
+        price: Decimal::from_str(price).unwrap(),
+        quantity: Decimal::from_str(quantity).unwrap(),
+    }
+}
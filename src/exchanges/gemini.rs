@@ -0,0 +1,223 @@
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::sink::SinkExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use tokio_stream::{Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel, Side},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+}
+
+/// Gemini symbol of the pair, e.g. `BTCUSD`
+pub fn symbol(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}{}",
+        base_currency.to_uppercase(),
+        quote_currency.to_uppercase()
+    )
+}
+
+/// `[side, price, quantity]` entry of `l2_updates`, zero quantity removes the level
+#[derive(Debug, PartialEq, Eq)]
+struct Change {
+    side: Side,
+    level: PriceLevel,
+}
+impl<'de> Deserialize<'de> for Change {
+    fn deserialize<D>(deserializer: D) -> Result<Change, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (side, price, quantity): (String, String, String) =
+            Deserialize::deserialize(deserializer)?;
+
+        Ok(Change {
+            side: match side.as_str() {
+                "buy" => Side::Bid,
+                "sell" => Side::Ask,
+                other => return Err(serde::de::Error::custom(format!("Unknown side {other:?}"))),
+            },
+            level: PriceLevel {
+                price: Decimal::from_str_exact(&price).map_err(serde::de::Error::custom)?,
+                quantity: Decimal::from_str_exact(&quantity).map_err(serde::de::Error::custom)?,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    L2Updates {
+        symbol: String,
+        changes: Vec<Change>,
+    },
+    Heartbeat,
+    Trade,
+    #[serde(other)]
+    Other,
+}
+
+/// Book maintained from `l2` subscription, the first `l2_updates` of the symbol is the whole book
+///
+/// Gemini doesn't acknowledge subscriptions, so the stream starts right after subscribe is sent.
+/// Only top `depth` levels per side are emitted after each message
+pub async fn get_l2_stream(
+    url: Url,
+    symbol: &str,
+    depth: usize,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let symbol = symbol.to_owned();
+
+    info!("Connect to gemini by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    ws.send(Message::Text(
+        json!({
+            "type": "subscribe",
+            "subscriptions": [{ "name": "l2", "symbols": [symbol] }],
+        })
+        .to_string(),
+    ))
+    .await?;
+
+    info!("Send subscribe for {symbol}");
+
+    let mut book = LocalOrderBook::default();
+
+    Ok(ws.filter_map(move |event| match event {
+        Ok(Message::Text(text)) => match serde_json::from_str::<'_, Response>(&text) {
+            Ok(Response::L2Updates {
+                symbol: update_symbol,
+                changes,
+            }) if update_symbol == symbol => {
+                changes
+                    .into_iter()
+                    .for_each(|Change { side, level }| book.apply(side, level));
+                Some(Ok(book.top(depth)))
+            }
+            Ok(response) => {
+                trace!("Skip {response:?}");
+                None
+            }
+            Err(error) => {
+                error!("{error:?}");
+                Some(Err(error.into()))
+            }
+        },
+        Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => None,
+        Ok(other) => {
+            warn!("Unexpected message {other:?}");
+            None
+        }
+        Err(err) => {
+            error!("Error while handle gemini ws: {err:?}");
+            Some(Err(Error::from(err)))
+        }
+    }))
+}
+
+pub struct Gemini {
+    pub ws_url: Url,
+    /// Levels per side emitted from the local book
+    pub depth: usize,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Gemini {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_l2_stream(
+            self.ws_url.clone(),
+            &symbol(base_currency, quote_currency),
+            self.depth,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_l2_stream(self.ws_url.clone(), &instrument.symbol, self.depth).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    #[test]
+    fn test_symbol() {
+        assert_eq!(symbol("btc", "usd"), "BTCUSD");
+    }
+
+    #[tokio::test]
+    async fn test_l2_stream() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                json!({
+                    "type": "subscribe",
+                    "subscriptions": [{ "name": "l2", "symbols": ["BTCUSD"] }]
+                })
+            );
+
+            stand_in::send_frames(
+                ws,
+                vec![
+                    r#"{"type":"l2_updates","symbol":"BTCUSD","changes":[["buy","100.00","1.5"],["buy","99.50","2"],["buy","99.00","3"],["sell","101.00","1"],["sell","101.50","2"]],"trades":[],"auction_events":[]}"#,
+                    r#"{"type":"trade","symbol":"BTCUSD","event_id":3575573053,"timestamp":1679306400000,"price":"100.00","quantity":"0.5","side":"sell"}"#,
+                    r#"{"type":"heartbeat","timestamp":1679306401000}"#,
+                    r#"{"type":"l2_updates","symbol":"ETHUSD","changes":[["buy","1.00","1"]]}"#,
+                    r#"{"type":"l2_updates","symbol":"BTCUSD","changes":[["buy","100.00","0"],["sell","100.50","4"]]}"#,
+                ]
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            )
+            .await
+        })
+        .await;
+
+        let source = Gemini { ws_url, depth: 2 };
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.bids,
+            vec![level("100", "1.5"), level("99.5", "2")]
+        );
+        assert_eq!(
+            order_book.asks,
+            vec![level("101", "1"), level("101.5", "2")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("99.5", "2"), level("99", "3")]);
+        assert_eq!(
+            order_book.asks,
+            vec![level("100.5", "4"), level("101", "1")]
+        );
+    }
+}
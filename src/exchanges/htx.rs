@@ -0,0 +1,382 @@
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::sink::SinkExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Number};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use super::{compression::Compression, ORDER_BOOKS_CHANNEL_SIZE};
+use crate::{
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, OrderBook, PriceLevel},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error("Failed to decompress frame: {0}")]
+    Decompression(#[from] std::io::Error),
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+}
+
+/// Price aggregation of `depth` topic, `Step0` is not aggregated
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Step {
+    Step0,
+    Step1,
+    Step2,
+    Step3,
+    Step4,
+    Step5,
+}
+impl Step {
+    fn name(&self) -> &'static str {
+        match self {
+            Step::Step0 => "step0",
+            Step::Step1 => "step1",
+            Step::Step2 => "step2",
+            Step::Step3 => "step3",
+            Step::Step4 => "step4",
+            Step::Step5 => "step5",
+        }
+    }
+}
+
+/// HTX symbol of the pair, e.g. `btcusdt`
+pub fn symbol(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}{}",
+        base_currency.to_lowercase(),
+        quote_currency.to_lowercase()
+    )
+}
+
+/// HTX sends `[price, amount]` levels as numbers instead of strings
+struct Level(PriceLevel);
+impl<'de> Deserialize<'de> for Level {
+    fn deserialize<D>(deserializer: D) -> Result<Level, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let decimal = |number: Number| -> Result<Decimal, D::Error> {
+            Decimal::from_str_exact(&number.to_string())
+                .or_else(|_| Decimal::from_scientific(&number.to_string()))
+                .map_err(serde::de::Error::custom)
+        };
+
+        let (price, quantity): (Number, Number) = Deserialize::deserialize(deserializer)?;
+        Ok(Level(PriceLevel {
+            price: decimal(price)?,
+            quantity: decimal(quantity)?,
+        }))
+    }
+}
+
+fn levels<'de, D>(deserializer: D) -> Result<Vec<PriceLevel>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Vec::<Level>::deserialize(deserializer)?
+        .into_iter()
+        .map(|Level(level)| level)
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct Tick {
+    #[serde(deserialize_with = "levels")]
+    bids: Vec<PriceLevel>,
+    #[serde(deserialize_with = "levels")]
+    asks: Vec<PriceLevel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Response {
+    /// Heartbeat of the server, must be answered with `pong` of the same value
+    Ping {
+        ping: u64,
+    },
+    Push {
+        ch: String,
+        tick: Tick,
+    },
+    Subscription {
+        status: String,
+    },
+}
+
+/// Decode a data frame, HTX compresses every frame with gzip
+fn decode(message: &Message) -> Option<Result<(Response, String), Error>> {
+    Some(
+        Compression::Gzip
+            .text(message)?
+            .map_err(Error::from)
+            .and_then(|text| Ok((serde_json::from_str(&text)?, text))),
+    )
+}
+
+/// Book of `market.{symbol}.depth.{step}` topic, every push is a snapshot of the top 150 levels
+///
+/// Pings of the server are answered with pongs, otherwise it closes the connection
+pub async fn get_depth_stream(
+    url: Url,
+    symbol: &str,
+    step: Step,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let topic = format!("market.{symbol}.depth.{step}", step = step.name());
+
+    info!("Connect to htx by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    ws.send(Message::Text(
+        json!({ "sub": topic, "id": topic }).to_string(),
+    ))
+    .await?;
+
+    info!("Send subscribe for {topic}");
+
+    while let Some(event) = ws.next().await {
+        let event = event?;
+        match decode(&event).transpose()? {
+            Some((Response::Ping { ping }, _)) => {
+                ws.send(Message::Text(json!({ "pong": ping }).to_string()))
+                    .await?
+            }
+            Some((Response::Subscription { status }, _)) if status == "ok" => break,
+            Some((Response::Subscription { .. }, text)) => {
+                return Err(Error::SubscriptionNotSuccess { response: text })
+            }
+            Some((response, _)) => trace!("Skip {response:?}"),
+            None => match event {
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => {
+                    warn!(
+                        "Unexpected message {other:?}, expected response to attempt to subscribe"
+                    );
+                    continue;
+                }
+            },
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+
+    tokio::spawn(
+        async move {
+            while let Some(event) = ws.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        error!("Error while handle htx ws: {err:?}");
+                        let _ = sender.send(Err(Error::from(err))).await;
+                        return;
+                    }
+                };
+
+                let order_book = match decode(&event) {
+                    Some(Ok((Response::Ping { ping }, _))) => {
+                        trace!("Send pong {ping}");
+                        let pong = Message::Text(json!({ "pong": ping }).to_string());
+                        if let Err(err) = ws.send(pong).await {
+                            error!("Error while pong htx: {err:?}");
+                            let _ = sender.send(Err(Error::from(err))).await;
+                            return;
+                        }
+                        continue;
+                    }
+                    Some(Ok((Response::Push { ch, tick }, _))) if ch == topic => Ok(OrderBook {
+                        bids: tick.bids,
+                        asks: tick.asks,
+                    }),
+                    Some(Ok((response, _))) => {
+                        trace!("Skip {response:?}");
+                        continue;
+                    }
+                    Some(Err(error)) => {
+                        error!("{error:?}");
+                        Err(error)
+                    }
+                    None => match event {
+                        Message::Ping(_) | Message::Pong(_) => continue,
+                        other => {
+                            warn!("Unexpected message {other:?}");
+                            continue;
+                        }
+                    },
+                };
+
+                if sender.send(order_book).await.is_err() {
+                    return;
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+pub struct Htx {
+    pub ws_url: Url,
+    pub step: Step,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Htx {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_depth_stream(
+            self.ws_url.clone(),
+            &symbol(base_currency, quote_currency),
+            self.step,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_depth_stream(self.ws_url.clone(), &instrument.symbol, self.step).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    fn gzip(value: serde_json::Value) -> Message {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(value.to_string().as_bytes()).unwrap();
+        Message::Binary(encoder.finish().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_depth_stream() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                json!({ "sub": "market.btcusdt.depth.step0", "id": "market.btcusdt.depth.step0" })
+            );
+
+            for message in [
+                json!({ "ping": 1492420473027u64 }),
+                json!({
+                    "id": "market.btcusdt.depth.step0",
+                    "status": "ok",
+                    "subbed": "market.btcusdt.depth.step0",
+                    "ts": 1492420473028u64,
+                }),
+            ] {
+                ws.send(gzip(message)).await.unwrap();
+            }
+
+            let pong = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&pong).unwrap(),
+                json!({ "pong": 1492420473027u64 })
+            );
+
+            for message in [
+                json!({
+                    "ch": "market.btcusdt.depth.step0",
+                    "ts": 1492420473100u64,
+                    "tick": {
+                        "bids": [[100.5, 1.25], [100, 2]],
+                        "asks": [[101, 0.0001], [101.5, 3]],
+                        "version": 100434317651u64,
+                        "ts": 1492420473090u64,
+                    },
+                }),
+                json!({ "ping": 1492420478027u64 }),
+                json!({
+                    "ch": "market.btcusdt.depth.step0",
+                    "ts": 1492420478100u64,
+                    "tick": {
+                        "bids": [[100, 2]],
+                        "asks": [[101.5, 3]],
+                        "version": 100434317652u64,
+                        "ts": 1492420478090u64,
+                    },
+                }),
+            ] {
+                ws.send(gzip(message)).await.unwrap();
+            }
+
+            let pong = stand_in::receive_text(&mut ws).await.unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&pong).unwrap(),
+                json!({ "pong": 1492420478027u64 })
+            );
+
+            stand_in::send_frames(ws, vec![]).await
+        })
+        .await;
+
+        let source = Htx {
+            ws_url,
+            step: Step::Step0,
+        };
+        let mut stream = source.get_order_books_stream("btc", "usdt").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.bids,
+            vec![level("100.5", "1.25"), level("100", "2")]
+        );
+        assert_eq!(
+            order_book.asks,
+            vec![level("101", "0.0001"), level("101.5", "3")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "2")]);
+        assert_eq!(order_book.asks, vec![level("101.5", "3")]);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_error() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            ws.send(gzip(json!({
+                "status": "error",
+                "ts": 1492420473028u64,
+                "err-code": "bad-request",
+                "err-msg": "invalid topic market.abcdef.depth.step0",
+            })))
+            .await
+            .unwrap();
+            stand_in::send_frames(ws, vec![]).await
+        })
+        .await;
+
+        let source = Htx {
+            ws_url,
+            step: Step::Step0,
+        };
+        assert!(matches!(
+            source.get_order_books_stream("abc", "def").await,
+            Err(Error::SubscriptionNotSuccess { .. })
+        ));
+    }
+}
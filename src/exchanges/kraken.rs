@@ -0,0 +1,697 @@
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::sink::SinkExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel, Side},
+};
+
+/// Levels per side covered by the checksum
+const CHECKSUM_DEPTH: usize = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+    #[error("Symbol {symbol} is not listed by kraken")]
+    UnknownSymbol { symbol: String },
+    #[error("Connection is closed before the subscription is confirmed")]
+    ConnectionClosed,
+    #[error("Book checksum {actual} doesn't match {expected} sent by kraken, resubscribe")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Depth {
+    _10,
+    _25,
+    _100,
+    _500,
+    _1000,
+}
+impl From<Depth> for usize {
+    fn from(value: Depth) -> Self {
+        match value {
+            Depth::_10 => 10,
+            Depth::_25 => 25,
+            Depth::_100 => 100,
+            Depth::_500 => 500,
+            Depth::_1000 => 1000,
+        }
+    }
+}
+
+/// Kraken websocket v2 symbol of the pair, e.g. `BTC/USD`
+pub fn symbol(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}/{}",
+        base_currency.to_uppercase(),
+        quote_currency.to_uppercase()
+    )
+}
+
+/// Prices and quantities are json numbers, taken by their shortest representation
+fn decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let number = serde_json::Number::deserialize(deserializer)?.to_string();
+    if number.contains(['e', 'E']) {
+        Decimal::from_scientific(&number)
+    } else {
+        Decimal::from_str_exact(&number)
+    }
+    .map_err(serde::de::Error::custom)
+}
+
+/// `{"price": .., "qty": ..}` entry, zero quantity removes the level
+#[derive(Debug, Deserialize)]
+struct Level {
+    #[serde(deserialize_with = "decimal")]
+    price: Decimal,
+    #[serde(deserialize_with = "decimal")]
+    qty: Decimal,
+}
+impl From<Level> for PriceLevel {
+    fn from(Level { price, qty }: Level) -> Self {
+        PriceLevel {
+            price,
+            quantity: qty,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BookData {
+    symbol: String,
+    #[serde(default)]
+    bids: Vec<Level>,
+    #[serde(default)]
+    asks: Vec<Level>,
+    checksum: u32,
+}
+
+/// Decimal places of prices and quantities of the pair, which the checksum is computed at
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+struct Precision {
+    price_precision: u32,
+    qty_precision: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct PairInfo {
+    symbol: String,
+    #[serde(flatten)]
+    precision: Precision,
+}
+
+#[derive(Debug, Deserialize)]
+struct Instruments {
+    pairs: Vec<PairInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MessageType {
+    Snapshot,
+    Update,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "channel", rename_all = "snake_case")]
+enum Channel {
+    Book {
+        #[serde(rename = "type")]
+        message_type: MessageType,
+        data: Vec<BookData>,
+    },
+    Instrument {
+        #[serde(rename = "type")]
+        message_type: MessageType,
+        data: Instruments,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionResult {
+    channel: String,
+}
+
+/// Response to a request, e.g. `subscribe`
+#[derive(Debug, Deserialize)]
+struct MethodResponse {
+    method: String,
+    #[serde(default)]
+    success: bool,
+    result: Option<SubscriptionResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Response {
+    Method(MethodResponse),
+    Channel(Channel),
+}
+
+/// CRC32 of the top levels, asks from the lowest price first, then bids from the highest one
+///
+/// Each price and quantity is formatted at the precision of the pair,
+/// then taken without the decimal point and leading zeros
+fn checksum(book: &LocalOrderBook, precision: Precision) -> u32 {
+    let OrderBook { bids, asks } = book.top(CHECKSUM_DEPTH);
+
+    let mut hasher = crc32fast::Hasher::new();
+    asks.iter().chain(bids.iter()).for_each(|level| {
+        [
+            (level.price, precision.price_precision),
+            (level.quantity, precision.qty_precision),
+        ]
+        .into_iter()
+        .for_each(|(mut value, scale)| {
+            value.rescale(scale);
+            hasher.update(
+                value
+                    .to_string()
+                    .replace('.', "")
+                    .trim_start_matches('0')
+                    .as_bytes(),
+            )
+        })
+    });
+
+    hasher.finalize()
+}
+
+/// Book of the subscribed depth, kept in sync with checksums
+#[derive(Debug)]
+struct KrakenBook {
+    book: LocalOrderBook,
+    depth: usize,
+    precision: Precision,
+}
+impl KrakenBook {
+    fn apply(&mut self, message_type: &MessageType, data: BookData) -> Result<(), Error> {
+        if let MessageType::Snapshot = message_type {
+            self.book.clear();
+        }
+
+        for (side, levels) in [(Side::Ask, data.asks), (Side::Bid, data.bids)] {
+            levels
+                .into_iter()
+                .for_each(|level| self.book.apply(side, level.into()));
+        }
+
+        // Kraken doesn't send removal of levels pushed out of the subscribed depth
+        self.book.truncate(self.depth);
+
+        match checksum(&self.book, self.precision) {
+            actual if actual != data.checksum => Err(Error::ChecksumMismatch {
+                expected: data.checksum,
+                actual,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn subscription(method: &str, symbol: &str, depth: usize) -> Message {
+    Message::Text(
+        json!({
+            "method": method,
+            "params": { "channel": "book", "symbol": [symbol], "depth": depth },
+        })
+        .to_string(),
+    )
+}
+
+/// Wait for the response to subscription to `channel`, any messages before it are skipped
+async fn subscribed<S>(ws: &mut S, channel: &str) -> Result<(), Error>
+where
+    S: Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>> + Unpin,
+{
+    while let Some(event) = ws.next().await {
+        match event? {
+            Message::Text(text) => match serde_json::from_str::<Response>(&text)? {
+                Response::Method(MethodResponse {
+                    method,
+                    success,
+                    result,
+                }) if method == "subscribe" => match result {
+                    Some(result) if success && result.channel == channel => return Ok(()),
+                    Some(_) if success => continue,
+                    _ => return Err(Error::SubscriptionNotSuccess { response: text }),
+                },
+                response => trace!("Skip {response:?}"),
+            },
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => {
+                warn!("Unexpected message {other:?}, expected response to attempt to subscribe");
+                continue;
+            }
+        }
+    }
+
+    Err(Error::ConnectionClosed)
+}
+
+/// Book of `book` channel, a snapshot followed by updates each with the checksum of the book
+///
+/// The precision of the pair, at which checksums are computed, is taken from `instrument` channel
+/// before subscribing. On checksum mismatch the error is sent to the stream and the book is
+/// resubscribed for a new snapshot
+pub async fn get_book_stream(
+    url: Url,
+    symbol: &str,
+    depth: Depth,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let symbol = symbol.to_owned();
+    let depth = usize::from(depth);
+
+    info!("Connect to kraken by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    ws.send(Message::Text(
+        json!({
+            "method": "subscribe",
+            "params": { "channel": "instrument", "snapshot": true },
+        })
+        .to_string(),
+    ))
+    .await?;
+    subscribed(&mut ws, "instrument").await?;
+
+    let precision = loop {
+        let Some(event) = ws.next().await else {
+            return Err(Error::ConnectionClosed);
+        };
+        if let Message::Text(text) = event? {
+            if let Response::Channel(Channel::Instrument {
+                message_type: MessageType::Snapshot,
+                data,
+            }) = serde_json::from_str::<Response>(&text)?
+            {
+                break data
+                    .pairs
+                    .into_iter()
+                    .find(|pair| pair.symbol == symbol)
+                    .map(|pair| pair.precision)
+                    .ok_or_else(|| Error::UnknownSymbol {
+                        symbol: symbol.clone(),
+                    })?;
+            }
+        }
+    };
+
+    debug!("Precision of {symbol} is {precision:?}");
+
+    ws.send(subscription("subscribe", &symbol, depth)).await?;
+
+    info!("Send subscribe for {symbol}");
+
+    subscribed(&mut ws, "book").await?;
+
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+
+    tokio::spawn(
+        async move {
+            // `None` until the snapshot of the current subscription
+            let mut book = None::<KrakenBook>;
+
+            while let Some(event) = ws.next().await {
+                let (message_type, data) = match event {
+                    Ok(Message::Text(text)) => match serde_json::from_str::<'_, Response>(&text) {
+                        Ok(Response::Channel(Channel::Book { message_type, data })) => {
+                            (message_type, data)
+                        }
+                        Ok(response) => {
+                            trace!("Skip {response:?}");
+                            continue;
+                        }
+                        Err(error) => {
+                            error!("{error:?}");
+                            if sender.send(Err(error.into())).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                    },
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+                    Ok(other) => {
+                        warn!("Unexpected message {other:?}");
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Error while handle kraken ws: {err:?}");
+                        let _ = sender.send(Err(Error::from(err))).await;
+                        return;
+                    }
+                };
+
+                let applied = data
+                    .into_iter()
+                    .filter(|data| data.symbol == symbol)
+                    .try_for_each(|data| match (book.as_mut(), &message_type) {
+                        (Some(book), message_type) => book.apply(message_type, data),
+                        (None, MessageType::Snapshot) => {
+                            let mut snapshot = KrakenBook {
+                                book: LocalOrderBook::default(),
+                                depth,
+                                precision,
+                            };
+                            snapshot.apply(&message_type, data)?;
+                            book = Some(snapshot);
+                            Ok(())
+                        }
+                        (None, MessageType::Update) => {
+                            trace!("Skip update received before snapshot");
+                            Ok(())
+                        }
+                    });
+
+                let order_book = match applied {
+                    Ok(()) => match &book {
+                        Some(book) => Ok(book.book.to_order_book()),
+                        None => continue,
+                    },
+                    Err(err @ Error::ChecksumMismatch { .. }) => {
+                        warn!("{err}");
+                        book = None;
+
+                        let resubscribed = async {
+                            ws.send(subscription("unsubscribe", &symbol, depth)).await?;
+                            ws.send(subscription("subscribe", &symbol, depth)).await
+                        };
+                        if let Err(err) = resubscribed.await {
+                            error!("Error while resubscribe to kraken book: {err:?}");
+                            let _ = sender.send(Err(Error::from(err))).await;
+                            return;
+                        }
+
+                        Err(err)
+                    }
+                    Err(err) => {
+                        error!("{err:?}");
+                        Err(err)
+                    }
+                };
+
+                if sender.send(order_book).await.is_err() {
+                    return;
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+pub struct Kraken {
+    pub ws_url: Url,
+    pub depth: Depth,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Kraken {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_book_stream(
+            self.ws_url.clone(),
+            &symbol(base_currency, quote_currency),
+            self.depth,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_book_stream(self.ws_url.clone(), &instrument.symbol, self.depth).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    const PRECISION: Precision = Precision {
+        price_precision: 1,
+        qty_precision: 8,
+    };
+
+    #[test]
+    fn test_symbol() {
+        assert_eq!(symbol("btc", "usd"), "BTC/USD");
+        assert_eq!(symbol("eth", "btc"), "ETH/BTC");
+    }
+
+    #[test]
+    fn test_level() {
+        let Level { price, qty } =
+            serde_json::from_str(r#"{"price": 0.05005, "qty": 1e-8}"#).unwrap();
+        assert_eq!(price, Decimal::from_str("0.05005").unwrap());
+        assert_eq!(qty, Decimal::from_str("0.00000001").unwrap());
+    }
+
+    #[test]
+    fn test_checksum() {
+        // Example of the kraken documentation, quantities are sent as `0.000005`
+        let level = |price: &str| level(price, "0.000005");
+        let book = LocalOrderBook::from(OrderBook {
+            asks: [
+                "0.05005", "0.0501", "0.05015", "0.0502", "0.05025", "0.0503", "0.05035", "0.0504",
+                "0.05045", "0.0505",
+            ]
+            .map(level)
+            .to_vec(),
+            bids: [
+                "0.05", "0.04995", "0.0499", "0.0498", "0.04975", "0.0497", "0.04965", "0.0496",
+                "0.04955", "0.0495",
+            ]
+            .map(level)
+            .to_vec(),
+        });
+
+        let precision = Precision {
+            price_precision: 5,
+            qty_precision: 8,
+        };
+        assert_eq!(checksum(&book, precision), 974947235);
+    }
+
+    fn book_message(
+        message_type: &str,
+        levels: serde_json::Value,
+        book: &LocalOrderBook,
+    ) -> String {
+        let mut data = levels;
+        data["symbol"] = "BTC/USD".into();
+        data["checksum"] = checksum(book, PRECISION).into();
+        json!({"channel": "book", "type": message_type, "data": [data]}).to_string()
+    }
+
+    fn subscribed_response(channel: &str) -> String {
+        json!({
+            "method": "subscribe",
+            "result": { "channel": channel, "snapshot": true },
+            "success": true,
+            "time_in": "2023-09-25T09:04:31.742599Z",
+            "time_out": "2023-09-25T09:04:31.742648Z"
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_book_stream() {
+        let snapshot = OrderBook {
+            bids: vec![level("100.0", "1.00"), level("99.5", "2.00")],
+            asks: vec![level("101.0", "1.00"), level("101.5", "2.00")],
+        };
+        let mut updated = LocalOrderBook::from(snapshot.clone());
+        updated.apply(Side::Bid, level("100.0", "0.00"));
+        updated.apply(Side::Ask, level("100.5", "3.00"));
+        let resubscribed = OrderBook {
+            bids: vec![level("98.0", "1.00")],
+            asks: vec![level("102.0", "1.00")],
+        };
+
+        let frames = vec![
+            subscribed_response("book"),
+            book_message(
+                "snapshot",
+                json!({
+                    "asks": [{"price": 101.0, "qty": 1.0}, {"price": 101.5, "qty": 2.0}],
+                    "bids": [{"price": 100.0, "qty": 1.0}, {"price": 99.5, "qty": 2.0}]
+                }),
+                &LocalOrderBook::from(snapshot.clone()),
+            ),
+            json!({"channel": "heartbeat"}).to_string(),
+            book_message(
+                "update",
+                json!({
+                    "asks": [{"price": 100.5, "qty": 3.0}],
+                    "bids": [{"price": 100.0, "qty": 0.0}]
+                }),
+                &updated,
+            ),
+            // Checksum of the stale book
+            book_message(
+                "update",
+                json!({"asks": [], "bids": [{"price": 99.0, "qty": 1.0}]}),
+                &LocalOrderBook::from(snapshot),
+            ),
+        ];
+
+        let ws_url = stand_in::serve_ws(move |mut ws| {
+            let frames = frames.clone();
+            let resubscribed = resubscribed.clone();
+            async move {
+                let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+                assert_eq!(
+                    serde_json::from_str::<serde_json::Value>(&subscribe).unwrap()["params"]
+                        ["channel"],
+                    "instrument"
+                );
+                let instruments = json!({
+                    "channel": "instrument",
+                    "type": "snapshot",
+                    "data": {
+                        "assets": [],
+                        "pairs": [
+                            {"symbol": "ETH/USD", "price_precision": 2, "qty_precision": 8},
+                            {"symbol": "BTC/USD", "price_precision": 1, "qty_precision": 8}
+                        ]
+                    }
+                });
+                for frame in [
+                    json!({"channel": "status", "type": "update", "data": []}).to_string(),
+                    subscribed_response("instrument"),
+                    instruments.to_string(),
+                ] {
+                    ws.send(Message::Text(frame)).await.unwrap();
+                }
+
+                let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+                assert_eq!(
+                    serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                    json!({
+                        "method": "subscribe",
+                        "params": { "channel": "book", "symbol": ["BTC/USD"], "depth": 10 }
+                    })
+                );
+
+                for frame in frames {
+                    ws.send(Message::Text(frame)).await.unwrap();
+                }
+
+                let unsubscribe = stand_in::receive_text(&mut ws).await.unwrap();
+                assert_eq!(
+                    serde_json::from_str::<serde_json::Value>(&unsubscribe).unwrap()["method"],
+                    "unsubscribe"
+                );
+                let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+                assert_eq!(
+                    serde_json::from_str::<serde_json::Value>(&subscribe).unwrap()["method"],
+                    "subscribe"
+                );
+
+                stand_in::send_frames(
+                    ws,
+                    vec![book_message(
+                        "snapshot",
+                        json!({
+                            "asks": [{"price": 102.0, "qty": 1.0}],
+                            "bids": [{"price": 98.0, "qty": 1.0}]
+                        }),
+                        &LocalOrderBook::from(resubscribed),
+                    )],
+                )
+                .await
+            }
+        })
+        .await;
+
+        let source = Kraken {
+            ws_url,
+            depth: Depth::_10,
+        };
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1"), level("99.5", "2")]);
+        assert_eq!(
+            order_book.asks,
+            vec![level("101", "1"), level("101.5", "2")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("99.5", "2")]);
+        assert_eq!(
+            order_book.asks,
+            vec![level("100.5", "3"), level("101", "1"), level("101.5", "2")]
+        );
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(Error::ChecksumMismatch { .. }))
+        ));
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("98", "1")]);
+        assert_eq!(order_book.asks, vec![level("102", "1")]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_symbol() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            stand_in::send_frames(
+                ws,
+                vec![
+                    subscribed_response("instrument"),
+                    json!({
+                        "channel": "instrument",
+                        "type": "snapshot",
+                        "data": { "assets": [], "pairs": [] }
+                    })
+                    .to_string(),
+                ],
+            )
+            .await
+        })
+        .await;
+
+        let source = Kraken {
+            ws_url,
+            depth: Depth::_10,
+        };
+        assert!(matches!(
+            source.get_order_books_stream("abc", "def").await,
+            Err(Error::UnknownSymbol { .. })
+        ));
+    }
+}
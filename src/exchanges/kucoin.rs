@@ -0,0 +1,712 @@
+//! Local order book maintained from `/market/level2` increments
+//!
+//! Follows the kucoin guide for the level 2 market data: the websocket endpoint is obtained
+//! by `bullet-public`, increments are buffered while a REST snapshot is fetched,
+//! changes not newer than the snapshot are dropped and every next increment must
+//! continue the previous one, otherwise the book is resynchronized from a new snapshot.
+
+use std::{collections::VecDeque, time::Duration};
+
+use async_tungstenite::{
+    tokio::{connect_async as ws_connect, ConnectStream},
+    tungstenite::Message,
+    WebSocketStream,
+};
+use futures_util::sink::SinkExt;
+use rust_decimal::Decimal;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_json::json;
+use tokio::{sync::mpsc, time::Interval};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel, Side},
+};
+
+/// Code of successful REST responses
+const SUCCESS_CODE: &str = "200000";
+
+/// Levels per side of `level2_100` snapshots, deeper levels of the local book are incomplete
+/// (the full snapshot endpoint requires authentication), so they are not published
+const SNAPSHOT_DEPTH: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error("Kucoin responded with code {code}: {msg:?}")]
+    Api { code: String, msg: Option<String> },
+    #[error("No instance servers in the bullet-public response")]
+    NoInstanceServers,
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+    #[error("Connection is closed before the subscription is confirmed")]
+    ConnectionClosed,
+    #[error("Increment from {sequence_start} does not continue the book, expected {expected}")]
+    OutOfSync { expected: u64, sequence_start: u64 },
+}
+
+/// Kucoin symbol of the pair, e.g. `BTC-USDT`
+pub fn symbol(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}-{}",
+        base_currency.to_uppercase(),
+        quote_currency.to_uppercase()
+    )
+}
+
+/// Kucoin sends the snapshot sequence as a string
+fn sequence<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// REST response envelope, `data` is present only with [`SUCCESS_CODE`]
+#[derive(Debug, Deserialize)]
+struct RestResponse<T> {
+    code: String,
+    data: Option<T>,
+    msg: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InstanceServer {
+    endpoint: String,
+    /// Interval of pings expected by the server, in milliseconds
+    ping_interval: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bullet {
+    token: String,
+    instance_servers: Vec<InstanceServer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Snapshot {
+    #[serde(deserialize_with = "sequence")]
+    sequence: u64,
+    #[serde(flatten)]
+    order_book: OrderBook,
+}
+
+/// Change of a level, `[price, size, sequence]`, zero size removes the level
+#[derive(Debug)]
+struct Change {
+    level: PriceLevel,
+    sequence: u64,
+}
+impl<'de> Deserialize<'de> for Change {
+    fn deserialize<D>(deserializer: D) -> Result<Change, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let (price, quantity, sequence): (String, String, String) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(Change {
+            level: PriceLevel {
+                price: Decimal::from_str_exact(&price).map_err(D::Error::custom)?,
+                quantity: Decimal::from_str_exact(&quantity).map_err(D::Error::custom)?,
+            },
+            sequence: sequence.parse().map_err(D::Error::custom)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Changes {
+    bids: Vec<Change>,
+    asks: Vec<Change>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Increment {
+    changes: Changes,
+    sequence_start: u64,
+    sequence_end: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Welcome,
+    Ack {
+        id: String,
+    },
+    Pong,
+    Message {
+        topic: String,
+        data: Increment,
+    },
+    Error,
+    #[serde(other)]
+    Other,
+}
+
+/// Local book with the sequence of the last applied change
+#[derive(Debug)]
+struct KucoinBook {
+    book: LocalOrderBook,
+    sequence: u64,
+}
+
+impl From<Snapshot> for KucoinBook {
+    fn from(snapshot: Snapshot) -> Self {
+        Self {
+            book: LocalOrderBook::from(snapshot.order_book),
+            sequence: snapshot.sequence,
+        }
+    }
+}
+
+impl KucoinBook {
+    /// Apply increment to the book, changes not newer than the book are skipped
+    ///
+    /// Returns `Ok(false)` if the whole increment is older than the book and was dropped,
+    /// [`Error::OutOfSync`] if some increments were lost and the book must be resynchronized
+    fn apply(&mut self, increment: Increment) -> Result<bool, Error> {
+        if increment.sequence_end <= self.sequence {
+            return Ok(false);
+        }
+
+        let expected = self.sequence + 1;
+        if increment.sequence_start > expected {
+            return Err(Error::OutOfSync {
+                expected,
+                sequence_start: increment.sequence_start,
+            });
+        }
+
+        let sequence = self.sequence;
+        let changes = increment
+            .changes
+            .bids
+            .into_iter()
+            .map(|change| (Side::Bid, change))
+            .chain(
+                increment
+                    .changes
+                    .asks
+                    .into_iter()
+                    .map(|change| (Side::Ask, change)),
+            );
+        for (side, change) in changes.filter(|(_, change)| change.sequence > sequence) {
+            self.book.apply(side, change.level);
+        }
+
+        self.sequence = increment.sequence_end;
+
+        Ok(true)
+    }
+}
+
+async fn fetch<T: DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T, Error> {
+    let response = request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<RestResponse<T>>()
+        .await?;
+
+    match response {
+        RestResponse {
+            code,
+            data: Some(data),
+            ..
+        } if code == SUCCESS_CODE => Ok(data),
+        RestResponse { code, msg, .. } => Err(Error::Api { code, msg }),
+    }
+}
+
+async fn fetch_bullet(rest_url: &Url) -> Result<Bullet, Error> {
+    let url = rest_url.join("api/v1/bullet-public")?;
+
+    debug!("Fetch kucoin public token by {url}");
+
+    fetch(reqwest::Client::new().post(url)).await
+}
+
+async fn fetch_snapshot(rest_url: &Url, symbol: &str) -> Result<Snapshot, Error> {
+    let mut url = rest_url.join(&format!("api/v1/market/orderbook/level2_{SNAPSHOT_DEPTH}"))?;
+    url.query_pairs_mut().append_pair("symbol", symbol);
+
+    debug!("Fetch kucoin snapshot by {url}");
+
+    fetch(reqwest::Client::new().get(url)).await
+}
+
+/// Next increment of `topic`, pings are sent by `ping` meanwhile
+///
+/// Returns `None` when the connection is closed
+async fn next_increment(
+    ws: &mut WebSocketStream<ConnectStream>,
+    ping: &mut Interval,
+    connect_id: &str,
+    topic: &str,
+) -> Option<Result<Increment, Error>> {
+    loop {
+        let event = tokio::select! {
+            _ = ping.tick() => {
+                trace!("Send ping");
+                let ping_message =
+                    Message::Text(json!({ "id": connect_id, "type": "ping" }).to_string());
+                if let Err(err) = ws.send(ping_message).await {
+                    return Some(Err(err.into()));
+                }
+                continue;
+            }
+            event = ws.next() => event?,
+        };
+
+        match event {
+            Ok(Message::Text(text)) => match serde_json::from_str::<'_, Response>(&text) {
+                Ok(Response::Message { topic: from, data }) if from == topic => {
+                    return Some(Ok(data))
+                }
+                Ok(response) => trace!("Skip {response:?}"),
+                Err(error) => return Some(Err(error.into())),
+            },
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+            Ok(other) => warn!("Unexpected message {other:?}"),
+            Err(err) => return Some(Err(err.into())),
+        }
+    }
+}
+
+/// Book of `/market/level2:{symbol}` topic, resynchronized by REST snapshots
+pub async fn get_level2_stream(
+    rest_url: Url,
+    symbol: &str,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let symbol = symbol.to_owned();
+    let topic = format!("/market/level2:{symbol}");
+
+    let bullet = fetch_bullet(&rest_url).await?;
+    let server = bullet
+        .instance_servers
+        .into_iter()
+        .next()
+        .ok_or(Error::NoInstanceServers)?;
+
+    let ping_interval = Duration::from_millis(server.ping_interval);
+
+    info!("Connect to kucoin by {}", server.endpoint);
+
+    let connect_id = rand::random::<u64>().to_string();
+    let mut ws_url = Url::parse(&server.endpoint)?;
+    ws_url
+        .query_pairs_mut()
+        .append_pair("token", &bullet.token)
+        .append_pair("connectId", &connect_id);
+
+    let (mut ws, _) = ws_connect(ws_url).await?;
+
+    loop {
+        let event = ws.next().await.ok_or(Error::ConnectionClosed)?;
+        match event? {
+            Message::Text(text) => match serde_json::from_str::<Response>(&text)? {
+                Response::Welcome => {
+                    ws.send(Message::Text(
+                        json!({
+                            "id": connect_id,
+                            "type": "subscribe",
+                            "topic": topic,
+                            "privateChannel": false,
+                            "response": true,
+                        })
+                        .to_string(),
+                    ))
+                    .await?;
+
+                    info!("Send subscribe for {topic}");
+                }
+                Response::Ack { id } if id == connect_id => break,
+                Response::Error => return Err(Error::SubscriptionNotSuccess { response: text }),
+                response => trace!("Skip {response:?}"),
+            },
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => {
+                warn!("Unexpected message {other:?}, expected response to attempt to subscribe");
+                continue;
+            }
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+
+    tokio::spawn(
+        async move {
+            let mut ping = tokio::time::interval(ping_interval);
+            ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut buffer = VecDeque::new();
+
+            'resync: loop {
+                // Increments left buffered on resync are older than the new snapshot
+                buffer.clear();
+
+                let snapshot = fetch_snapshot(&rest_url, &symbol);
+                tokio::pin!(snapshot);
+
+                // Increments received meanwhile are buffered to be applied over the snapshot
+                let mut book = loop {
+                    tokio::select! {
+                        snapshot = &mut snapshot => match snapshot {
+                            Ok(snapshot) => break KucoinBook::from(snapshot),
+                            Err(err) => {
+                                error!("Error while fetch kucoin snapshot: {err:?}");
+                                let _ = sender.send(Err(err)).await;
+                                return;
+                            }
+                        },
+                        increment = next_increment(&mut ws, &mut ping, &connect_id, &topic) => {
+                            match increment {
+                                Some(Ok(increment)) => buffer.push_back(increment),
+                                Some(Err(err @ Error::Format(_))) => {
+                                    error!("{err:?}");
+                                    if sender.send(Err(err)).await.is_err() {
+                                        return;
+                                    }
+                                }
+                                Some(Err(err)) => {
+                                    error!("Error while handle kucoin ws: {err:?}");
+                                    let _ = sender.send(Err(err)).await;
+                                    return;
+                                }
+                                None => return,
+                            }
+                        }
+                    }
+                };
+
+                if sender
+                    .send(Ok(book.book.top(SNAPSHOT_DEPTH)))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+
+                loop {
+                    let increment = match buffer.pop_front() {
+                        Some(increment) => increment,
+                        None => match next_increment(&mut ws, &mut ping, &connect_id, &topic).await
+                        {
+                            Some(Ok(increment)) => increment,
+                            Some(Err(err @ Error::Format(_))) => {
+                                error!("{err:?}");
+                                if sender.send(Err(err)).await.is_err() {
+                                    return;
+                                }
+                                continue;
+                            }
+                            Some(Err(err)) => {
+                                error!("Error while handle kucoin ws: {err:?}");
+                                let _ = sender.send(Err(err)).await;
+                                return;
+                            }
+                            None => return,
+                        },
+                    };
+
+                    let order_book = match book.apply(increment) {
+                        Ok(true) => Ok(book.book.top(SNAPSHOT_DEPTH)),
+                        Ok(false) => continue,
+                        Err(Error::OutOfSync {
+                            expected,
+                            sequence_start,
+                        }) => {
+                            warn!(
+                                "Kucoin book out of sync, expected sequence {expected}, \
+                                 received {sequence_start}, resync"
+                            );
+                            continue 'resync;
+                        }
+                        Err(err) => Err(err),
+                    };
+
+                    if sender.send(order_book).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+pub struct Kucoin {
+    pub rest_url: Url,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Kucoin {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_level2_stream(
+            self.rest_url.clone(),
+            &symbol(base_currency, quote_currency),
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_level2_stream(self.rest_url.clone(), &instrument.symbol).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::assert_matches::assert_matches;
+
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    fn increment(
+        sequence_start: u64,
+        sequence_end: u64,
+        bids: &[[&str; 3]],
+        asks: &[[&str; 3]],
+    ) -> String {
+        json!({
+            "type": "message",
+            "topic": "/market/level2:BTC-USDT",
+            "subject": "trade.l2update",
+            "data": {
+                "changes": { "asks": asks, "bids": bids },
+                "sequenceEnd": sequence_end,
+                "sequenceStart": sequence_start,
+                "symbol": "BTC-USDT",
+                "time": 1663747970273u64,
+            },
+        })
+        .to_string()
+    }
+
+    fn snapshot(sequence: u64, bids: &[[&str; 2]], asks: &[[&str; 2]]) -> String {
+        json!({
+            "code": "200000",
+            "data": {
+                "time": 1663747970273u64,
+                "sequence": sequence.to_string(),
+                "bids": bids,
+                "asks": asks,
+            },
+        })
+        .to_string()
+    }
+
+    /// `bullet-public` response pointing to the websocket at `ws_url`
+    fn bullet(ws_url: &Url) -> String {
+        json!({
+            "code": "200000",
+            "data": {
+                "token": "token",
+                "instanceServers": [{
+                    "endpoint": ws_url.as_str(),
+                    "encrypt": false,
+                    "protocol": "websocket",
+                    "pingInterval": 18000,
+                    "pingTimeout": 10000,
+                }],
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_increment_must_continue_book() {
+        let mut book = KucoinBook::from(
+            serde_json::from_str::<RestResponse<Snapshot>>(&snapshot(100, &[], &[]))
+                .unwrap()
+                .data
+                .unwrap(),
+        );
+
+        let update = |text: String| {
+            serde_json::from_str::<Response>(&text)
+                .map(|response| match response {
+                    Response::Message { data, .. } => data,
+                    other => panic!("Unexpected {other:?}"),
+                })
+                .unwrap()
+        };
+
+        assert_matches!(
+            book.apply(update(increment(102, 105, &[], &[]))),
+            Err(Error::OutOfSync {
+                expected: 101,
+                sequence_start: 102
+            })
+        );
+        assert_matches!(book.apply(update(increment(90, 100, &[], &[]))), Ok(false));
+        assert_matches!(
+            book.apply(update(increment(
+                95,
+                101,
+                &[["100", "1", "95"], ["99", "1", "101"]],
+                &[]
+            ))),
+            Ok(true)
+        );
+        assert_eq!(book.book.to_order_book().bids, vec![level("99", "1")]);
+    }
+
+    #[tokio::test]
+    async fn test_level2_stream_resync() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            ws.send(Message::Text(
+                json!({ "id": "welcome", "type": "welcome" }).to_string(),
+            ))
+            .await
+            .unwrap();
+
+            let subscribe = serde_json::from_str::<serde_json::Value>(
+                &stand_in::receive_text(&mut ws).await.unwrap(),
+            )
+            .unwrap();
+            assert_eq!(subscribe["type"], "subscribe");
+            assert_eq!(subscribe["topic"], "/market/level2:BTC-USDT");
+
+            ws.send(Message::Text(
+                json!({ "id": subscribe["id"], "type": "ack" }).to_string(),
+            ))
+            .await
+            .unwrap();
+
+            stand_in::send_frames(
+                ws,
+                vec![
+                    // Older than the snapshot, dropped
+                    increment(95, 100, &[["100", "10", "100"]], &[]),
+                    increment(
+                        99,
+                        102,
+                        &[["100", "5", "99"], ["100", "3", "101"]],
+                        &[["101", "0", "102"]],
+                    ),
+                    increment(103, 104, &[["98", "1", "104"]], &[]),
+                    // Gap, leads to resync with the second snapshot
+                    increment(110, 111, &[["50", "1", "111"]], &[]),
+                    increment(119, 121, &[["96", "1", "121"]], &[["103", "2", "119"]]),
+                ],
+            )
+            .await
+        })
+        .await;
+
+        let (rest_url, requests) = stand_in::serve_http(vec![
+            bullet(&ws_url),
+            snapshot(
+                100,
+                &[["100", "1"], ["99", "2"]],
+                &[["101", "1"], ["102", "2"]],
+            ),
+            snapshot(120, &[["97", "1"]], &[["103", "1"]]),
+        ])
+        .await;
+
+        let source = Kucoin { rest_url };
+        let mut stream = source.get_order_books_stream("btc", "usdt").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1"), level("99", "2")]);
+        assert_eq!(order_book.asks, vec![level("101", "1"), level("102", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "3"), level("99", "2")]);
+        assert_eq!(order_book.asks, vec![level("102", "2")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            order_book.bids,
+            vec![level("100", "3"), level("99", "2"), level("98", "1")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1")]);
+        assert_eq!(order_book.asks, vec![level("103", "1")]);
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("97", "1"), level("96", "1")]);
+        assert_eq!(order_book.asks, vec![level("103", "1")]);
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "/api/v1/bullet-public",
+                "/api/v1/market/orderbook/level2_100?symbol=BTC-USDT",
+                "/api/v1/market/orderbook/level2_100?symbol=BTC-USDT",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bullet_error() {
+        let (rest_url, _) = stand_in::serve_http(vec![json!({
+            "code": "400100",
+            "msg": "Too many requests",
+        })
+        .to_string()])
+        .await;
+
+        let source = Kucoin { rest_url };
+        assert!(matches!(
+            source.get_order_books_stream("btc", "usdt").await,
+            Err(Error::Api { code, .. }) if code == "400100"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_closed_before_subscription() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            ws.send(Message::Text(
+                json!({ "id": "welcome", "type": "welcome" }).to_string(),
+            ))
+            .await
+            .unwrap();
+            stand_in::receive_text(&mut ws).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        let (rest_url, requests) = stand_in::serve_http(vec![bullet(&ws_url)]).await;
+
+        let source = Kucoin { rest_url };
+        assert!(matches!(
+            source.get_order_books_stream("btc", "usdt").await,
+            Err(Error::ConnectionClosed)
+        ));
+        // No snapshot is fetched for the closed connection
+        assert_eq!(*requests.lock().unwrap(), vec!["/api/v1/bullet-public"]);
+    }
+}
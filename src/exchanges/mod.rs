@@ -1,2 +1,20 @@
 pub mod binance;
+pub mod bitfinex;
 pub mod bitstamp;
+pub mod bybit;
+pub mod coinbase;
+pub mod compression;
+pub mod gemini;
+pub mod htx;
+pub mod kraken;
+pub mod kucoin;
+pub mod okx;
+pub mod reconnect;
+
+#[cfg(test)]
+pub(crate) mod fixtures;
+#[cfg(test)]
+mod stand_in;
+
+/// Capacity of channels passing order books from connection tasks to streams
+pub const ORDER_BOOKS_CHANNEL_SIZE: usize = 16;
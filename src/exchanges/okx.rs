@@ -0,0 +1,584 @@
+use std::time::Duration;
+
+use async_tungstenite::{tokio::connect_async as ws_connect, tungstenite::Message};
+use futures_util::sink::SinkExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::*;
+use url::Url;
+
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, LocalOrderBook, OrderBook, PriceLevel, Side},
+};
+
+/// Levels per side covered by the checksum
+const CHECKSUM_DEPTH: usize = 25;
+
+/// OKX closes connections silent for 30 seconds, so `ping` is sent more often than that
+const PING_INTERVAL: Duration = Duration::from_secs(25);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] async_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error("Subscription was unsuccessful, the server responded: {response:?}")]
+    SubscriptionNotSuccess { response: String },
+    #[error("Connection is closed before the subscription is confirmed")]
+    ConnectionClosed,
+    #[error("Update {prev_seq_id} does not continue the book, expected {expected}, resubscribe")]
+    OutOfSync { expected: i64, prev_seq_id: i64 },
+    #[error("Book checksum {actual} doesn't match {expected} sent by okx, resubscribe")]
+    ChecksumMismatch { expected: i32, actual: i32 },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Channel {
+    /// `books`, snapshot of 400 levels followed by incremental updates
+    Books,
+    /// `books5`, snapshots of the top 5 levels
+    Books5,
+}
+impl Channel {
+    fn name(&self) -> &'static str {
+        match self {
+            Channel::Books => "books",
+            Channel::Books5 => "books5",
+        }
+    }
+}
+
+/// OKX instrument id of the spot pair, e.g. `BTC-USDT`
+pub fn instrument_id(base_currency: &str, quote_currency: &str) -> String {
+    format!(
+        "{}-{}",
+        base_currency.to_uppercase(),
+        quote_currency.to_uppercase()
+    )
+}
+
+/// `[price, size, deprecated, orders]` entry, zero size removes the level
+#[derive(Debug, PartialEq, Eq)]
+struct Level(PriceLevel);
+impl<'de> Deserialize<'de> for Level {
+    fn deserialize<D>(deserializer: D) -> Result<Level, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (price, quantity) = match Vec::<String>::deserialize(deserializer)?.as_slice() {
+            [price, quantity, ..] => (price.clone(), quantity.clone()),
+            other => {
+                return Err(serde::de::Error::custom(format!(
+                    "Expected at least price and size, got {other:?}"
+                )))
+            }
+        };
+
+        Ok(Level(PriceLevel {
+            price: Decimal::from_str_exact(&price).map_err(serde::de::Error::custom)?,
+            quantity: Decimal::from_str_exact(&quantity).map_err(serde::de::Error::custom)?,
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BookData {
+    asks: Vec<Level>,
+    bids: Vec<Level>,
+    checksum: Option<i32>,
+    prev_seq_id: Option<i64>,
+    seq_id: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Arg {
+    channel: String,
+    inst_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Snapshot,
+    Update,
+}
+
+#[derive(Debug, Deserialize)]
+struct Push {
+    arg: Arg,
+    /// Not set for `books5`, every push of which is a snapshot
+    action: Option<Action>,
+    data: Vec<BookData>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Subscribe,
+    Error,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Response {
+    Push(Push),
+    Event(Event),
+}
+
+/// Signed CRC32 of `bid:size:ask:size:..` of the top levels, as sent by okx
+///
+/// Levels are interleaved while both sides have them, then the rest of the longer side follows
+fn checksum(book: &LocalOrderBook) -> i32 {
+    let OrderBook { bids, asks } = book.top(CHECKSUM_DEPTH);
+
+    let mut bids = bids.into_iter();
+    let mut asks = asks.into_iter();
+    let mut values = vec![];
+    loop {
+        match (bids.next(), asks.next()) {
+            (None, None) => break,
+            (bid, ask) => bid.into_iter().chain(ask).for_each(|level| {
+                values.push(level.price.to_string());
+                values.push(level.quantity.to_string());
+            }),
+        }
+    }
+
+    crc32fast::hash(values.join(":").as_bytes()) as i32
+}
+
+/// Book kept in sync by `seqId` continuity and checksums
+#[derive(Debug, Default)]
+struct OkxBook {
+    book: LocalOrderBook,
+    seq_id: Option<i64>,
+}
+impl OkxBook {
+    fn apply(&mut self, action: &Action, data: BookData) -> Result<(), Error> {
+        match action {
+            Action::Snapshot => self.book.clear(),
+            Action::Update => {
+                if let (Some(expected), Some(prev_seq_id)) = (self.seq_id, data.prev_seq_id) {
+                    if prev_seq_id != expected {
+                        return Err(Error::OutOfSync {
+                            expected,
+                            prev_seq_id,
+                        });
+                    }
+                }
+            }
+        }
+
+        for (side, levels) in [(Side::Ask, data.asks), (Side::Bid, data.bids)] {
+            levels
+                .into_iter()
+                .for_each(|Level(level)| self.book.apply(side, level));
+        }
+        self.seq_id = data.seq_id;
+
+        match data.checksum {
+            Some(expected) => match checksum(&self.book) {
+                actual if actual != expected => Err(Error::ChecksumMismatch { expected, actual }),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+fn subscription(op: &str, channel: Channel, instrument_id: &str) -> Message {
+    Message::Text(
+        json!({
+            "op": op,
+            "args": [{ "channel": channel.name(), "instId": instrument_id }],
+        })
+        .to_string(),
+    )
+}
+
+/// Book of `books` or `books5` channel, the former is kept in sync by `seqId` and checksums
+///
+/// When the book is out of sync, the error is sent to the stream and the book is resubscribed for a new snapshot
+pub async fn get_books_stream(
+    url: Url,
+    instrument_id: &str,
+    channel: Channel,
+) -> Result<impl Stream<Item = Result<OrderBook, Error>>, Error> {
+    let instrument_id = instrument_id.to_owned();
+    let arg = Arg {
+        channel: channel.name().to_owned(),
+        inst_id: instrument_id.clone(),
+    };
+
+    info!("Connect to okx by {url}");
+
+    let (mut ws, _) = ws_connect(url).await?;
+    ws.send(subscription("subscribe", channel, &instrument_id))
+        .await?;
+
+    info!("Send subscribe for {instrument_id}");
+
+    loop {
+        let event = ws.next().await.ok_or(Error::ConnectionClosed)?;
+        match event? {
+            Message::Text(text) => match serde_json::from_str::<Response>(&text)? {
+                Response::Event(Event::Subscribe) => break,
+                Response::Event(Event::Error) => {
+                    return Err(Error::SubscriptionNotSuccess { response: text })
+                }
+                response => trace!("Skip {response:?}"),
+            },
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => {
+                warn!("Unexpected message {other:?}, expected response to attempt to subscribe");
+                continue;
+            }
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+
+    tokio::spawn(
+        async move {
+            // `None` until the snapshot of the current subscription
+            let mut book = None::<OkxBook>;
+            let mut ping = tokio::time::interval_at(
+                tokio::time::Instant::now() + PING_INTERVAL,
+                PING_INTERVAL,
+            );
+            ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                let event = tokio::select! {
+                    event = ws.next() => match event {
+                        Some(event) => event,
+                        None => return,
+                    },
+                    _ = ping.tick() => {
+                        if let Err(err) = ws.send(Message::Text("ping".to_owned())).await {
+                            error!("Error while ping okx: {err:?}");
+                            let _ = sender.send(Err(Error::from(err))).await;
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                let push = match event {
+                    Ok(Message::Text(text)) if text == "pong" => continue,
+                    Ok(Message::Text(text)) => match serde_json::from_str::<'_, Response>(&text) {
+                        Ok(Response::Push(push)) if push.arg == arg => push,
+                        Ok(response) => {
+                            trace!("Skip {response:?}");
+                            continue;
+                        }
+                        Err(error) => {
+                            error!("{error:?}");
+                            if sender.send(Err(error.into())).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                    },
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+                    Ok(other) => {
+                        warn!("Unexpected message {other:?}");
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Error while handle okx ws: {err:?}");
+                        let _ = sender.send(Err(Error::from(err))).await;
+                        return;
+                    }
+                };
+
+                let action = push.action.unwrap_or(Action::Snapshot);
+                let applied =
+                    push.data
+                        .into_iter()
+                        .try_for_each(|data| match (book.as_mut(), &action) {
+                            (Some(book), action) => book.apply(action, data),
+                            (None, Action::Snapshot) => {
+                                let mut snapshot = OkxBook::default();
+                                snapshot.apply(&action, data)?;
+                                book = Some(snapshot);
+                                Ok(())
+                            }
+                            (None, Action::Update) => {
+                                trace!("Skip update received before snapshot");
+                                Ok(())
+                            }
+                        });
+
+                let order_book = match applied {
+                    Ok(()) => match &book {
+                        Some(book) => Ok(book.book.to_order_book()),
+                        None => continue,
+                    },
+                    Err(err @ (Error::OutOfSync { .. } | Error::ChecksumMismatch { .. })) => {
+                        warn!("{err}");
+                        book = None;
+
+                        let resubscribed = async {
+                            ws.send(subscription("unsubscribe", channel, &instrument_id))
+                                .await?;
+                            ws.send(subscription("subscribe", channel, &instrument_id))
+                                .await
+                        };
+                        if let Err(err) = resubscribed.await {
+                            error!("Error while resubscribe to okx books: {err:?}");
+                            let _ = sender.send(Err(Error::from(err))).await;
+                            return;
+                        }
+
+                        Err(err)
+                    }
+                    Err(err) => {
+                        error!("{err:?}");
+                        Err(err)
+                    }
+                };
+
+                if sender.send(order_book).await.is_err() {
+                    return;
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+pub struct Okx {
+    pub ws_url: Url,
+    pub channel: Channel,
+}
+
+#[tonic::async_trait]
+impl GetOrderBooksStream for Okx {
+    type Error = Error;
+    type OrderBooksStream = impl Stream<Item = Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_books_stream(
+            self.ws_url.clone(),
+            &instrument_id(base_currency, quote_currency),
+            self.channel,
+        )
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        get_books_stream(self.ws_url.clone(), &instrument.symbol, self.channel).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::{fixtures::level, stand_in};
+
+    #[test]
+    fn test_instrument_id() {
+        assert_eq!(instrument_id("btc", "usdt"), "BTC-USDT");
+    }
+
+    #[test]
+    fn test_checksum() {
+        let book = LocalOrderBook::from(OrderBook {
+            bids: vec![level("3366.1", "7"), level("3366", "6")],
+            asks: vec![level("3366.8", "9"), level("3368", "8")],
+        });
+        // crc32 of "3366.1:7:3366.8:9:3366:6:3368:8"
+        assert_eq!(checksum(&book), -1881014294);
+
+        let book = LocalOrderBook::from(OrderBook {
+            bids: vec![level("3366.1", "7"), level("3366", "6")],
+            asks: vec![level("3366.8", "9"), level("3368", "8"), level("3372", "8")],
+        });
+        // crc32 of "3366.1:7:3366.8:9:3366:6:3368:8:3372:8"
+        assert_eq!(checksum(&book), 1362239393);
+    }
+
+    fn push(
+        action: &str,
+        prev_seq_id: i64,
+        seq_id: i64,
+        bids: &[[&str; 4]],
+        asks: &[[&str; 4]],
+        book: &LocalOrderBook,
+    ) -> String {
+        json!({
+            "arg": { "channel": "books", "instId": "BTC-USDT" },
+            "action": action,
+            "data": [{
+                "asks": asks,
+                "bids": bids,
+                "ts": "1597026383085",
+                "checksum": checksum(book),
+                "prevSeqId": prev_seq_id,
+                "seqId": seq_id,
+            }]
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_books_stream() {
+        let snapshot = OrderBook {
+            bids: vec![level("100.0", "1"), level("99.5", "2")],
+            asks: vec![level("101.0", "1"), level("101.5", "2")],
+        };
+        let mut updated = LocalOrderBook::from(snapshot.clone());
+        updated.apply(Side::Bid, level("100.0", "0"));
+        updated.apply(Side::Ask, level("100.5", "3"));
+        let resubscribed = LocalOrderBook::from(OrderBook {
+            bids: vec![level("98.0", "1")],
+            asks: vec![level("102.0", "1")],
+        });
+
+        let frames = vec![
+            json!({
+                "event": "subscribe",
+                "arg": { "channel": "books", "instId": "BTC-USDT" },
+                "connId": "a4d3ae55"
+            })
+            .to_string(),
+            push(
+                "snapshot",
+                -1,
+                10,
+                &[["100.0", "1", "0", "1"], ["99.5", "2", "0", "1"]],
+                &[["101.0", "1", "0", "1"], ["101.5", "2", "0", "1"]],
+                &LocalOrderBook::from(snapshot),
+            ),
+            push(
+                "update",
+                10,
+                11,
+                &[["100.0", "0", "0", "0"]],
+                &[["100.5", "3", "0", "1"]],
+                &updated,
+            ),
+            // Update 12 is missed
+            push("update", 12, 13, &[["99.0", "1", "0", "1"]], &[], &updated),
+        ];
+
+        let ws_url = stand_in::serve_ws(move |mut ws| {
+            let frames = frames.clone();
+            let resubscribed = resubscribed.clone();
+            async move {
+                let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+                assert_eq!(
+                    serde_json::from_str::<serde_json::Value>(&subscribe).unwrap(),
+                    json!({
+                        "op": "subscribe",
+                        "args": [{ "channel": "books", "instId": "BTC-USDT" }]
+                    })
+                );
+
+                for frame in frames {
+                    ws.send(Message::Text(frame)).await.unwrap();
+                }
+
+                let unsubscribe = stand_in::receive_text(&mut ws).await.unwrap();
+                assert_eq!(
+                    serde_json::from_str::<serde_json::Value>(&unsubscribe).unwrap()["op"],
+                    "unsubscribe"
+                );
+                let subscribe = stand_in::receive_text(&mut ws).await.unwrap();
+                assert_eq!(
+                    serde_json::from_str::<serde_json::Value>(&subscribe).unwrap()["op"],
+                    "subscribe"
+                );
+
+                stand_in::send_frames(
+                    ws,
+                    vec![
+                        // Update of the previous subscription before the new snapshot
+                        push("update", 13, 14, &[["97.0", "1", "0", "1"]], &[], &updated),
+                        push(
+                            "snapshot",
+                            -1,
+                            20,
+                            &[["98.0", "1", "0", "1"]],
+                            &[["102.0", "1", "0", "1"]],
+                            &resubscribed,
+                        ),
+                    ],
+                )
+                .await
+            }
+        })
+        .await;
+
+        let source = Okx {
+            ws_url,
+            channel: Channel::Books,
+        };
+        let mut stream = source.get_order_books_stream("btc", "usdt").await.unwrap();
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("100", "1"), level("99.5", "2")]);
+        assert_eq!(
+            order_book.asks,
+            vec![level("101", "1"), level("101.5", "2")]
+        );
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("99.5", "2")]);
+        assert_eq!(
+            order_book.asks,
+            vec![level("100.5", "3"), level("101", "1"), level("101.5", "2")]
+        );
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(Error::OutOfSync {
+                expected: 11,
+                prev_seq_id: 12
+            }))
+        ));
+
+        let order_book = stream.next().await.unwrap().unwrap();
+        assert_eq!(order_book.bids, vec![level("98", "1")]);
+        assert_eq!(order_book.asks, vec![level("102", "1")]);
+    }
+
+    #[tokio::test]
+    async fn test_closed_before_subscription() {
+        let ws_url = stand_in::serve_ws(|mut ws| async move {
+            stand_in::receive_text(&mut ws).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+
+        let source = Okx {
+            ws_url,
+            channel: Channel::Books,
+        };
+        assert!(matches!(
+            source.get_order_books_stream("btc", "usdt").await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rand::Rng;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tracing::*;
+
+use crate::{
+    exchanges::ORDER_BOOKS_CHANNEL_SIZE,
+    instruments::Instrument,
+    order_book::{GetOrderBooksStream, OrderBook},
+};
+
+/// Exponential backoff between reconnect attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Consecutive failed attempts after which the source is given up, `None` to retry forever
+    pub max_retries: Option<u32>,
+}
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_retries: None,
+        }
+    }
+}
+impl Backoff {
+    /// Delay before the `attempt` (counting from zero), jittered within `[delay / 2, delay]`
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Counters of reconnects, shared by all streams of one [`Reconnecting`] source
+#[derive(Debug, Default)]
+pub struct ReconnectStats {
+    pub reconnects: AtomicU64,
+    pub failed_attempts: AtomicU64,
+}
+
+/// Wrapper around any source of orderbooks that re-opens its stream when it ends
+///
+/// Streams are re-opened the way they were first opened, by the pair or by the instrument
+///
+/// The first [`GetOrderBooksStream::get_order_books_stream`] call is not retried,
+/// so that misconfiguration (e.g. unsupported pair) is reported immediately
+pub struct Reconnecting<G> {
+    source: Arc<G>,
+    backoff: Backoff,
+    stats: Arc<ReconnectStats>,
+}
+
+impl<G> Reconnecting<G> {
+    pub fn new(source: G, backoff: Backoff) -> Self {
+        Self {
+            source: Arc::new(source),
+            backoff,
+            stats: Arc::default(),
+        }
+    }
+}
+
+/// How the stream of the source is opened, kept to re-open it the same way
+enum Subscription {
+    Pair {
+        base_currency: String,
+        quote_currency: String,
+    },
+    Instrument(Instrument),
+}
+impl Subscription {
+    async fn open<G: GetOrderBooksStream + Sync>(
+        &self,
+        source: &G,
+    ) -> Result<G::OrderBooksStream, G::Error> {
+        match self {
+            Subscription::Pair {
+                base_currency,
+                quote_currency,
+            } => {
+                source
+                    .get_order_books_stream(base_currency, quote_currency)
+                    .await
+            }
+            Subscription::Instrument(instrument) => {
+                source.get_instrument_order_books_stream(instrument).await
+            }
+        }
+    }
+}
+
+impl<G> Reconnecting<G>
+where
+    G: GetOrderBooksStream + Send + Sync + 'static,
+    G::Error: Debug + Send + 'static,
+    G::OrderBooksStream: Unpin + Send + 'static,
+{
+    async fn subscribe(
+        &self,
+        subscription: Subscription,
+    ) -> Result<ReceiverStream<Result<OrderBook, G::Error>>, G::Error> {
+        let mut stream = subscription.open(self.source.as_ref()).await?;
+
+        let (sender, receiver) = mpsc::channel(ORDER_BOOKS_CHANNEL_SIZE);
+        let source = self.source.clone();
+        let backoff = self.backoff;
+        let stats = self.stats.clone();
+
+        tokio::spawn(
+            async move {
+                let mut attempt = 0;
+
+                loop {
+                    while let Some(order_book) = stream.next().await {
+                        if order_book.is_ok() {
+                            attempt = 0;
+                        }
+
+                        if sender.send(order_book).await.is_err() {
+                            return;
+                        }
+                    }
+
+                    warn!("Orderbooks stream ended");
+
+                    stream = loop {
+                        if backoff.max_retries.is_some_and(|max| attempt >= max) {
+                            error!("Give up reconnecting after {attempt} attempts");
+                            return;
+                        }
+
+                        let delay = backoff.delay(attempt);
+                        attempt += 1;
+                        info!("Reconnect attempt {attempt} in {delay:?}");
+
+                        tokio::select! {
+                            _ = sender.closed() => return,
+                            _ = tokio::time::sleep(delay) => {}
+                        }
+
+                        match subscription.open(source.as_ref()).await {
+                            Ok(stream) => {
+                                stats.reconnects.fetch_add(1, Ordering::Relaxed);
+                                info!("Reconnected on attempt {attempt}");
+                                break stream;
+                            }
+                            Err(err) => {
+                                stats.failed_attempts.fetch_add(1, Ordering::Relaxed);
+                                warn!("Reconnect attempt {attempt} failed: {err:?}");
+                            }
+                        }
+                    };
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        Ok(ReceiverStream::new(receiver))
+    }
+}
+
+#[tonic::async_trait]
+impl<G> GetOrderBooksStream for Reconnecting<G>
+where
+    G: GetOrderBooksStream + Send + Sync + 'static,
+    G::Error: Debug + Send + 'static,
+    G::OrderBooksStream: Unpin + Send + 'static,
+{
+    type Error = G::Error;
+    type OrderBooksStream = ReceiverStream<Result<OrderBook, Self::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        self.subscribe(Subscription::Pair {
+            base_currency: base_currency.to_owned(),
+            quote_currency: quote_currency.to_owned(),
+        })
+        .await
+    }
+
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        self.subscribe(Subscription::Instrument(instrument.clone()))
+            .await
+    }
+
+    fn reconnect_stats(&self) -> Option<Arc<ReconnectStats>> {
+        Some(self.stats.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::order_book::PriceLevel;
+
+    #[derive(Debug, thiserror::Error)]
+    enum Error {
+        #[error("Unavailable")]
+        Unavailable,
+    }
+
+    /// Source that succeeds only on the first and the third calls, with a single orderbook
+    struct FlakySource {
+        calls: Arc<AtomicUsize>,
+    }
+
+    fn order_book(price: usize) -> OrderBook {
+        OrderBook {
+            bids: vec![PriceLevel {
+                price: Decimal::from(price),
+                quantity: Decimal::ONE,
+            }],
+            asks: vec![],
+        }
+    }
+
+    #[tonic::async_trait]
+    impl GetOrderBooksStream for FlakySource {
+        type Error = Error;
+        type OrderBooksStream = tokio_stream::Iter<std::vec::IntoIter<Result<OrderBook, Error>>>;
+
+        async fn get_order_books_stream(
+            &self,
+            _base_currency: &str,
+            _quote_currency: &str,
+        ) -> Result<Self::OrderBooksStream, Self::Error> {
+            match self.calls.fetch_add(1, Ordering::SeqCst) {
+                call @ (0 | 2) => Ok(tokio_stream::iter(vec![Ok(order_book(call))])),
+                _ => Err(Error::Unavailable),
+            }
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_retries: None,
+        };
+
+        for (attempt, max) in [
+            (0, 100),
+            (1, 200),
+            (2, 400),
+            (3, 800),
+            (4, 1000),
+            (40, 1000),
+        ] {
+            let delay = backoff.delay(attempt);
+            assert!(delay <= Duration::from_millis(max), "{attempt}: {delay:?}");
+            assert!(
+                delay >= Duration::from_millis(max * 49 / 100),
+                "{attempt}: {delay:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let source = Reconnecting::new(
+            FlakySource {
+                calls: calls.clone(),
+            },
+            Backoff {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+                max_retries: Some(3),
+            },
+        );
+        let stats = source.reconnect_stats().unwrap();
+
+        let order_books = source
+            .get_order_books_stream("btc", "usdt")
+            .await
+            .unwrap()
+            .map(|order_book| order_book.unwrap().bids[0].price)
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(order_books, vec![Decimal::from(0), Decimal::from(2)]);
+        // First call, one failed attempt, reconnect and three more failed attempts
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+        assert_eq!(stats.reconnects.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.failed_attempts.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,125 @@
+//! Local stand-ins for exchange endpoints, so connectors can be tested without network
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use async_tungstenite::{
+    tokio::{accept_async, TokioAdapter},
+    tungstenite::Message,
+    WebSocketStream,
+};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use url::Url;
+
+pub type ServerWebSocket = WebSocketStream<TokioAdapter<TcpStream>>;
+
+/// Requests received by a stand-in, in order of arrival
+pub type Requests = Arc<Mutex<Vec<String>>>;
+
+/// Serve websocket connections on a random local port, `handler` is called for every connection
+///
+/// Returns url of the endpoint with `/ws` path
+pub async fn serve_ws<F, Fut>(handler: F) -> Url
+where
+    F: Fn(ServerWebSocket) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/ws", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            match accept_async(socket).await {
+                Ok(ws) => {
+                    tokio::spawn(handler(ws));
+                }
+                Err(err) => panic!("Stand-in failed to accept websocket: {err:?}"),
+            }
+        }
+    });
+
+    url
+}
+
+/// Send text `frames` and keep the connection open until the client hangs up
+pub async fn send_frames(mut ws: ServerWebSocket, frames: Vec<String>) {
+    for frame in frames {
+        if ws.send(Message::Text(frame)).await.is_err() {
+            return;
+        }
+    }
+
+    while let Some(Ok(_)) = ws.next().await {}
+}
+
+/// Wait for the next text frame from the client, e.g. subscribe request
+pub async fn receive_text(ws: &mut ServerWebSocket) -> Option<String> {
+    while let Some(Ok(message)) = ws.next().await {
+        if let Message::Text(text) = message {
+            return Some(text);
+        }
+    }
+
+    None
+}
+
+/// Serve HTTP requests on a random local port
+///
+/// Every request is answered with the next body of `responses`
+/// (the last one is repeated when they run out) as `application/json`.
+/// The request line target (path with query) of each request is stored into returned [`Requests`]
+pub async fn serve_http(responses: Vec<String>) -> (Url, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let requests = Requests::default();
+
+    tokio::spawn({
+        let requests = requests.clone();
+        async move {
+            let mut responses = responses.into_iter();
+            let mut body = String::new();
+
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+
+                requests.lock().unwrap().push(
+                    String::from_utf8_lossy(&request)
+                        .split_whitespace()
+                        .nth(1)
+                        .unwrap_or_default()
+                        .to_owned(),
+                );
+
+                if let Some(next) = responses.next() {
+                    body = next;
+                }
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {length}\r\nconnection: close\r\n\r\n{body}",
+                    length = body.len()
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        }
+    });
+
+    (url, requests)
+}
@@ -0,0 +1,540 @@
+//! Registry of instruments listed by exchanges, keyed by canonical pairs
+//!
+//! Exchanges name the same asset differently (e.g. `XBT` on kraken and `BTC` elsewhere), so
+//! pairs of the config are first canonicalized by aliases and then looked up in the listing
+//! of the exchange. Sources wrapped by [`Listed`] are opened by the native symbol of the
+//! instrument and fail right away if the exchange doesn't list the pair.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use futures_util::{stream::MapErr, TryStreamExt};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use tracing::*;
+
+use crate::{
+    exchanges::reconnect::ReconnectStats,
+    order_book::{GetOrderBooksStream, Pair, ParsePairError},
+    server::ExchangeName,
+};
+
+/// Aliases of assets used by exchanges, mapped to the canonical lowercase names
+const DEFAULT_ALIASES: &[(&str, &str)] = &[
+    ("xbt", "btc"),
+    ("xxbt", "btc"),
+    ("xdg", "doge"),
+    ("xxdg", "doge"),
+    ("xeth", "eth"),
+    ("zusd", "usd"),
+    ("zeur", "eur"),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("While read instruments: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+    #[error(transparent)]
+    Pair(#[from] ParsePairError),
+    #[error("Exchange {exchange_name} doesn't list {pair}")]
+    NotListed {
+        exchange_name: ExchangeName,
+        pair: Pair,
+    },
+    #[error("Instrument {symbol} of {pair} on {exchange_name} is {status}")]
+    NotTrading {
+        exchange_name: ExchangeName,
+        pair: Pair,
+        symbol: String,
+        status: Status,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Trading,
+    /// Listed, but trading is suspended for now
+    Halted,
+    Delisted,
+}
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Trading => "trading",
+            Status::Halted => "halted",
+            Status::Delisted => "delisted",
+        })
+    }
+}
+
+/// Pair as listed by an exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instrument {
+    /// Native symbol of the pair, e.g. `BTC/USD` on kraken, sources subscribe by it as is
+    pub symbol: String,
+    /// Native name of the base asset, for sources not subscribing by symbol
+    pub base_currency: String,
+    /// Native name of the quote asset, for sources not subscribing by symbol
+    pub quote_currency: String,
+    /// Minimal price step, in the quote currency
+    pub tick_size: Option<Decimal>,
+    /// Minimal quantity step, in the base currency
+    pub lot_size: Option<Decimal>,
+    pub status: Status,
+}
+
+/// Entry of the instruments file
+///
+/// ```json
+/// { "pair": "btc/usd", "symbol": "BTC/USD", "base": "XBT", "tick_size": "0.1", "status": "trading" }
+/// ```
+/// Native assets default to the ones of the canonical pair
+#[derive(Debug, Deserialize)]
+struct Listing {
+    pair: String,
+    symbol: String,
+    base: Option<String>,
+    quote: Option<String>,
+    #[serde(default, deserialize_with = "decimal")]
+    tick_size: Option<Decimal>,
+    #[serde(default, deserialize_with = "decimal")]
+    lot_size: Option<Decimal>,
+    #[serde(default)]
+    status: Status,
+}
+
+fn decimal<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| Decimal::from_str(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct InstrumentsFile {
+    /// Additional aliases of assets, e.g. `{"xbt": "btc"}`
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    /// Listings by exchange name
+    #[serde(default)]
+    exchanges: HashMap<ExchangeName, Vec<Listing>>,
+}
+
+/// Instruments of all exchanges, shared by all clones
+///
+/// Listings come from the instruments file and from exchanges listing their pairs themselves
+/// (bitstamp `trading-pairs-info`). Exchanges without a listing are not checked, their sources
+/// are opened with the pair as is
+#[derive(Debug, Clone)]
+pub struct Registry {
+    aliases: Arc<HashMap<String, String>>,
+    instruments: Arc<RwLock<HashMap<ExchangeName, HashMap<Pair, Instrument>>>>,
+}
+
+fn default_aliases() -> HashMap<String, String> {
+    DEFAULT_ALIASES
+        .iter()
+        .map(|(alias, asset)| (alias.to_string(), asset.to_string()))
+        .collect()
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            aliases: Arc::new(default_aliases()),
+            instruments: Arc::default(),
+        }
+    }
+}
+
+impl Registry {
+    /// Registry with default aliases and listings of the json file at `path`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file: InstrumentsFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+        let mut aliases = default_aliases();
+        aliases.extend(
+            file.aliases
+                .into_iter()
+                .map(|(alias, asset)| (alias.to_lowercase(), asset.to_lowercase())),
+        );
+        let registry = Self {
+            aliases: Arc::new(aliases),
+            instruments: Arc::default(),
+        };
+
+        for (exchange_name, listings) in file.exchanges {
+            let instruments = listings
+                .into_iter()
+                .map(|listing| {
+                    let pair = Pair::from_str(&listing.pair)?;
+                    let instrument = Instrument {
+                        symbol: listing.symbol,
+                        base_currency: listing.base.unwrap_or_else(|| pair.base_currency.clone()),
+                        quote_currency: listing
+                            .quote
+                            .unwrap_or_else(|| pair.quote_currency.clone()),
+                        tick_size: listing.tick_size,
+                        lot_size: listing.lot_size,
+                        status: listing.status,
+                    };
+                    Ok((pair, instrument))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            registry.insert(exchange_name.to_lowercase(), instruments);
+        }
+
+        Ok(registry)
+    }
+
+    /// Replace the listing of the exchange, pairs are canonicalized
+    pub fn insert(
+        &self,
+        exchange_name: ExchangeName,
+        instruments: impl IntoIterator<Item = (Pair, Instrument)>,
+    ) {
+        let instruments = instruments
+            .into_iter()
+            .map(|(pair, instrument)| (self.canonical_pair(&pair), instrument))
+            .collect::<HashMap<_, _>>();
+
+        info!(
+            "Registered {} instruments of {exchange_name}",
+            instruments.len()
+        );
+        self.instruments
+            .write()
+            .unwrap()
+            .insert(exchange_name, instruments);
+    }
+
+    /// Canonical lowercase name of the asset
+    pub fn canonical_asset(&self, asset: &str) -> String {
+        let asset = asset.to_lowercase();
+        self.aliases.get(&asset).cloned().unwrap_or(asset)
+    }
+
+    pub fn canonical_pair(&self, pair: &Pair) -> Pair {
+        Pair {
+            base_currency: self.canonical_asset(&pair.base_currency),
+            quote_currency: self.canonical_asset(&pair.quote_currency),
+        }
+    }
+
+    /// Instrument of the pair on the exchange, `None` if the exchange has no listing at all
+    ///
+    /// Pairs missing from the listing and instruments not in trading are errors
+    pub fn instrument(
+        &self,
+        exchange_name: &str,
+        pair: &Pair,
+    ) -> Result<Option<Instrument>, Error> {
+        let pair = self.canonical_pair(pair);
+        let instruments = self.instruments.read().unwrap();
+        let listing = match instruments.get(exchange_name) {
+            Some(listing) => listing,
+            None => return Ok(None),
+        };
+
+        match listing.get(&pair) {
+            Some(instrument) if instrument.status == Status::Trading => {
+                Ok(Some(instrument.clone()))
+            }
+            Some(instrument) => Err(Error::NotTrading {
+                exchange_name: exchange_name.to_owned(),
+                pair,
+                symbol: instrument.symbol.clone(),
+                status: instrument.status,
+            }),
+            None => Err(Error::NotListed {
+                exchange_name: exchange_name.to_owned(),
+                pair,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ListedError<E> {
+    #[error(transparent)]
+    Instrument(#[from] Error),
+    #[error(transparent)]
+    Source(E),
+}
+
+/// Wrapper around any source of orderbooks, which opens streams of instruments of the registry
+///
+/// The pair is resolved before the source is called, so an unlisted pair is reported without
+/// connecting to the exchange
+pub struct Listed<G> {
+    source: G,
+    exchange_name: ExchangeName,
+    registry: Registry,
+}
+
+impl<G> Listed<G> {
+    pub fn new(source: G, exchange_name: ExchangeName, registry: Registry) -> Self {
+        Self {
+            source,
+            exchange_name,
+            registry,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<G> GetOrderBooksStream for Listed<G>
+where
+    G: GetOrderBooksStream + Send + Sync,
+{
+    type Error = ListedError<G::Error>;
+    type OrderBooksStream = MapErr<G::OrderBooksStream, fn(G::Error) -> ListedError<G::Error>>;
+
+    async fn get_order_books_stream(
+        &self,
+        base_currency: &str,
+        quote_currency: &str,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        let pair = Pair::new(base_currency, quote_currency);
+        let stream = match self.registry.instrument(&self.exchange_name, &pair)? {
+            Some(instrument) => {
+                debug!(
+                    "Open {} of {pair} on {}",
+                    instrument.symbol, self.exchange_name
+                );
+                self.source
+                    .get_instrument_order_books_stream(&instrument)
+                    .await
+            }
+            None => {
+                self.source
+                    .get_order_books_stream(base_currency, quote_currency)
+                    .await
+            }
+        };
+
+        Ok(stream
+            .map_err(ListedError::Source)?
+            .map_err(ListedError::Source as fn(_) -> _))
+    }
+
+    fn reconnect_stats(&self) -> Option<Arc<ReconnectStats>> {
+        self.source.reconnect_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::Ordering, Mutex},
+        time::Duration,
+    };
+
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{
+        exchanges::reconnect::{Backoff, Reconnecting},
+        order_book::OrderBook,
+    };
+
+    fn instrument(symbol: &str, base: &str, quote: &str, status: Status) -> Instrument {
+        Instrument {
+            symbol: symbol.to_owned(),
+            base_currency: base.to_owned(),
+            quote_currency: quote.to_owned(),
+            tick_size: None,
+            lot_size: None,
+            status,
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("instruments-{}.json", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            serde_json::json!({
+                "aliases": { "XXRP": "xrp" },
+                "exchanges": {
+                    "kraken": [
+                        {
+                            "pair": "xbt/usd",
+                            "symbol": "XBT/USD",
+                            "base": "XBT",
+                            "tick_size": "0.1",
+                            "lot_size": "0.00000001",
+                        },
+                        { "pair": "xxrp/usd", "symbol": "XRP/USD", "status": "halted" },
+                    ],
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let registry = Registry::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            registry
+                .instrument("kraken", &Pair::new("BTC", "USD"))
+                .unwrap(),
+            Some(Instrument {
+                tick_size: Some(Decimal::new(1, 1)),
+                lot_size: Some(Decimal::new(1, 8)),
+                ..instrument("XBT/USD", "XBT", "usd", Status::Trading)
+            })
+        );
+        assert_eq!(
+            registry
+                .instrument("kraken", &Pair::new("xbt", "zusd"))
+                .unwrap(),
+            registry
+                .instrument("kraken", &Pair::new("btc", "usd"))
+                .unwrap()
+        );
+        assert!(matches!(
+            registry.instrument("kraken", &Pair::new("xrp", "usd")),
+            Err(Error::NotTrading {
+                status: Status::Halted,
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry.instrument("kraken", &Pair::new("eth", "usd")),
+            Err(Error::NotListed { .. })
+        ));
+        assert_eq!(
+            registry
+                .instrument("binance", &Pair::new("eth", "usd"))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Unavailable")]
+    struct Unavailable;
+
+    /// Source recording the pairs and the symbols it's opened with
+    #[derive(Default)]
+    struct RecordingSource {
+        opened: Mutex<Vec<String>>,
+    }
+
+    #[tonic::async_trait]
+    impl GetOrderBooksStream for RecordingSource {
+        type Error = Unavailable;
+        type OrderBooksStream =
+            tokio_stream::Iter<std::vec::IntoIter<Result<OrderBook, Unavailable>>>;
+
+        async fn get_order_books_stream(
+            &self,
+            base_currency: &str,
+            quote_currency: &str,
+        ) -> Result<Self::OrderBooksStream, Self::Error> {
+            self.opened
+                .lock()
+                .unwrap()
+                .push(format!("{base_currency}-{quote_currency}"));
+            Ok(tokio_stream::iter(vec![Err(Unavailable)]))
+        }
+
+        async fn get_instrument_order_books_stream(
+            &self,
+            instrument: &Instrument,
+        ) -> Result<Self::OrderBooksStream, Self::Error> {
+            self.opened.lock().unwrap().push(instrument.symbol.clone());
+            Ok(tokio_stream::iter(vec![Err(Unavailable)]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listed_source() {
+        let registry = Registry::default();
+        registry.insert(
+            "kraken".to_owned(),
+            [(
+                Pair::new("btc", "usd"),
+                instrument("BTC/USD", "XBT", "USD", Status::Trading),
+            )],
+        );
+
+        let source = Listed::new(
+            RecordingSource::default(),
+            "kraken".to_owned(),
+            registry.clone(),
+        );
+        let unlisted = Listed::new(RecordingSource::default(), "gemini".to_owned(), registry);
+
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(ListedError::Source(Unavailable)))
+        ));
+        assert!(matches!(
+            source.get_order_books_stream("eth", "usd").await,
+            Err(ListedError::Instrument(Error::NotListed { .. }))
+        ));
+        assert_eq!(*source.source.opened.lock().unwrap(), vec!["BTC/USD"]);
+
+        unlisted.get_order_books_stream("btc", "usd").await.unwrap();
+        assert_eq!(*unlisted.source.opened.lock().unwrap(), vec!["btc-usd"]);
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_delisted_source() {
+        let registry = Registry::default();
+        registry.insert(
+            "kraken".to_owned(),
+            [(
+                Pair::new("btc", "usd"),
+                instrument("BTC/USD", "XBT", "USD", Status::Trading),
+            )],
+        );
+
+        let source = Reconnecting::new(
+            Listed::new(
+                RecordingSource::default(),
+                "kraken".to_owned(),
+                registry.clone(),
+            ),
+            Backoff {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                max_retries: Some(2),
+            },
+        );
+        let stats = source.reconnect_stats().unwrap();
+
+        let mut stream = source.get_order_books_stream("btc", "usd").await.unwrap();
+        registry.insert(
+            "kraken".to_owned(),
+            [(
+                Pair::new("btc", "usd"),
+                instrument("BTC/USD", "XBT", "USD", Status::Delisted),
+            )],
+        );
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(ListedError::Source(Unavailable)))
+        ));
+        // Reconnects resolve the pair again instead of reopening the first instrument
+        assert!(stream.next().await.is_none());
+        assert_eq!(stats.reconnects.load(Ordering::SeqCst), 0);
+        assert_eq!(stats.failed_attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -3,7 +3,12 @@
 #![feature(assert_matches)]
 #![feature(result_option_inspect)]
 #![feature(is_sorted)]
-use std::error;
+use std::{
+    collections::HashMap,
+    error, iter,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use tracing::*;
 
@@ -11,6 +16,8 @@ use tracing::*;
 mod config;
 // A set of exchange modules
 mod exchanges;
+// Canonical pairs mapped to instruments listed by exchanges
+mod instruments;
 // Core structures independent of protobuf to simplify deserialisation
 // TODO In theory it could be shortened, but it's a bit easier than going direct with protobuf
 mod order_book;
@@ -29,9 +36,14 @@ enum Error {
     Log(Box<dyn error::Error + Send + Sync>),
     #[error(transparent)]
     ServerError(#[from] server::Error),
+    #[error("While load instruments: {0}")]
+    Instruments(#[from] instruments::Error),
 }
 
 use config::*;
+use exchanges::reconnect::Reconnecting;
+use order_book::Pair;
+use server::SourceFactory;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -45,33 +57,433 @@ async fn main() -> Result<(), Error> {
         config.summary_size,
     );
 
-    service
-        .add_orderbook_source(
-            "binance".to_owned(),
-            exchanges::binance::Binance {
-                ws_url: config.binance_websocket_addr,
-                depth: exchanges::binance::Depth::_10,
-            },
-        )
-        .instrument(span!(Level::TRACE, "Process binance orderbook"))
-        .await?;
-
-    service
-        .add_orderbook_source(
-            "bitstamp".to_owned(),
-            exchanges::bitstamp::Bitstamp::new(config.bitstamp_websocket_addr),
-        )
-        .instrument(span!(Level::TRACE, "Process bitstamp orderbook"))
-        .await?;
+    if config.fee_adjusted_summary {
+        service
+            .enable_fee_adjustment(config.taker_fees.0.clone())
+            .await;
+    }
+
+    if config.consolidated_summary {
+        service.enable_level_consolidation().await;
+    }
+
+    if let Some(threshold) = config.stale_book_threshold_ms.filter(|ms| *ms > 0) {
+        service
+            .enable_stale_books_eviction(Duration::from_millis(threshold))
+            .await;
+    }
+
+    let default_pair = Pair::new(&config.base_currency, &config.quote_currency);
+    let pairs = iter::once(default_pair)
+        .chain(config.additional_pairs.0.iter().cloned())
+        .collect::<Vec<_>>();
+    let addr = config.addr;
+    let admin_addr = config.admin_addr;
+    for (pair, rate) in &config.fx_rates.0 {
+        service.fx_rates().set(pair.clone(), *rate);
+    }
+    let sources = ExchangeSources::new(config)?;
+
+    for pair in &pairs {
+        for exchange_name in &sources.config.exchanges.0 {
+            sources
+                .add_source(&service, exchange_name.clone(), pair)
+                .await?;
+        }
+    }
+
+    for (pair, exchange_name) in &sources.config.fx_rate_sources.0 {
+        sources
+            .add_fx_rate_source(&service, exchange_name.clone(), pair)
+            .await?;
+    }
 
     let orderbook_aggregator_service =
-        proto::orderbook_aggregator_server::OrderbookAggregatorServer::new(service);
+        proto::orderbook_aggregator_server::OrderbookAggregatorServer::new(service.clone());
 
-    tonic::transport::Server::builder()
+    let serve = tonic::transport::Server::builder()
         .accept_http1(true)
         .add_service(orderbook_aggregator_service)
-        .serve(config.addr)
-        .instrument(span!(Level::TRACE, "Handle grpc service"))
-        .await
-        .map_err(Error::from)
+        .serve(addr)
+        .instrument(span!(Level::TRACE, "Handle grpc service"));
+
+    match admin_addr {
+        Some(admin_addr) => {
+            let serve_admin = tonic::transport::Server::builder()
+                .add_service(proto::orderbook_admin_server::OrderbookAdminServer::new(
+                    server::AdminService::new(service, sources),
+                ))
+                .serve(admin_addr)
+                .instrument(span!(Level::TRACE, "Handle grpc admin service"));
+
+            tokio::try_join!(serve, serve_admin)?;
+        }
+        None => serve.await?,
+    }
+
+    Ok(())
+}
+
+/// What books of a source are used for
+#[derive(Debug, Clone, Copy)]
+enum Feed<'q> {
+    /// Merged into the summary of the pair, the exchange books are quoted in `quote_currency`
+    Book { quote_currency: &'q str },
+    /// Kept as the fx rate of the pair
+    FxRate,
+}
+
+/// Sources of all supported exchanges, configured by [`Config`]
+struct ExchangeSources {
+    config: Config,
+    /// Connections of binance combined streams, shared by pairs of each market
+    binance_combined:
+        Mutex<HashMap<exchanges::binance::Market, exchanges::binance::BinanceCombined>>,
+    /// Pairs listed by bitstamp, loaded into the registry by the first bitstamp source and
+    /// refreshed periodically
+    bitstamp_trading_pairs: tokio::sync::OnceCell<exchanges::bitstamp::TradingPairs>,
+    /// Instruments of exchanges, consulted by every source before subscribing
+    instruments: instruments::Registry,
+}
+
+impl ExchangeSources {
+    fn new(config: Config) -> Result<Self, instruments::Error> {
+        let instruments = match &config.instruments_path {
+            Some(path) => instruments::Registry::load(path)?,
+            None => instruments::Registry::default(),
+        };
+
+        Ok(Self {
+            config,
+            binance_combined: Mutex::default(),
+            bitstamp_trading_pairs: tokio::sync::OnceCell::new(),
+            instruments,
+        })
+    }
+
+    /// Source opening streams of the exchange instrument resolved by the registry
+    fn listed<G>(&self, exchange_name: &str, source: G) -> instruments::Listed<G> {
+        instruments::Listed::new(source, exchange_name.to_owned(), self.instruments.clone())
+    }
+
+    async fn bitstamp_trading_pairs(
+        &self,
+    ) -> Result<exchanges::bitstamp::TradingPairs, exchanges::bitstamp::Error> {
+        let config = &self.config;
+        self.bitstamp_trading_pairs
+            .get_or_try_init(|| async {
+                let trading_pairs = exchanges::bitstamp::TradingPairs::load(
+                    self.instruments.clone(),
+                    &config.bitstamp_rest_addr,
+                    config.bitstamp_pairs_cache_path.as_deref(),
+                )
+                .await?;
+                trading_pairs.spawn_refresh(
+                    config.bitstamp_rest_addr.clone(),
+                    config.bitstamp_pairs_cache_path.clone(),
+                    Duration::from_secs(config.bitstamp_pairs_refresh_secs),
+                );
+                Ok::<_, exchanges::bitstamp::Error>(trading_pairs)
+            })
+            .await
+            .cloned()
+    }
+
+    /// Combined streams source of the market, connected by the first pair
+    fn binance_combined(
+        &self,
+        market: exchanges::binance::Market,
+        ws_url: &url::Url,
+    ) -> exchanges::binance::BinanceCombined {
+        self.binance_combined
+            .lock()
+            .unwrap()
+            .entry(market)
+            .or_insert_with(|| {
+                // Combined streams are served by `/stream` next to `/ws` of single streams
+                let ws_url = ws_url.join("stream").unwrap_or_else(|_| ws_url.clone());
+                exchanges::binance::BinanceCombined::new(
+                    ws_url,
+                    market,
+                    exchanges::binance::Depth::_10,
+                )
+            })
+            .clone()
+    }
+
+    /// Add source of the pair of the exchange books used as `feed`, the only place
+    /// where exchange connectors are configured
+    async fn add_feed(
+        &self,
+        service: &server::OrderbookAggregatorService,
+        exchange_name: server::ExchangeName,
+        pair: &Pair,
+        feed: Feed<'_>,
+    ) -> Result<(), server::Error> {
+        let config = &self.config;
+
+        match exchange_name.as_str() {
+            "binance" | "binance-usdm" | "binance-coinm" => {
+                let (market, ws_url, rest_url) = match exchange_name.as_str() {
+                    "binance-usdm" => (
+                        exchanges::binance::Market::UsdM,
+                        &config.binance_usdm_websocket_addr,
+                        &config.binance_usdm_rest_addr,
+                    ),
+                    "binance-coinm" => (
+                        exchanges::binance::Market::CoinM,
+                        &config.binance_coinm_websocket_addr,
+                        &config.binance_coinm_rest_addr,
+                    ),
+                    _ => (
+                        exchanges::binance::Market::Spot,
+                        &config.binance_websocket_addr,
+                        &config.binance_rest_addr,
+                    ),
+                };
+                let span = span!(Level::TRACE, "Process binance orderbook", %pair, ?market);
+
+                if config.binance_diff_depth {
+                    self.add(
+                        service,
+                        exchange_name,
+                        pair,
+                        feed,
+                        exchanges::binance::BinanceDiffDepth {
+                            ws_url: ws_url.clone(),
+                            rest_url: rest_url.clone(),
+                            market,
+                            snapshot_limit: config.binance_snapshot_limit,
+                            depth: config.summary_size,
+                        },
+                    )
+                    .instrument(span)
+                    .await
+                } else if config.binance_combined_streams {
+                    self.add(
+                        service,
+                        exchange_name,
+                        pair,
+                        feed,
+                        self.binance_combined(market, ws_url),
+                    )
+                    .instrument(span)
+                    .await
+                } else {
+                    self.add(
+                        service,
+                        exchange_name,
+                        pair,
+                        feed,
+                        exchanges::binance::Binance {
+                            ws_url: ws_url.clone(),
+                            market,
+                            depth: exchanges::binance::Depth::_10,
+                        },
+                    )
+                    .instrument(span)
+                    .await
+                }
+            }
+            "bitfinex" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::bitfinex::Bitfinex::new(config.bitfinex_websocket_addr.clone()),
+                )
+                .instrument(span!(Level::TRACE, "Process bitfinex orderbook", %pair))
+                .await
+            }
+            "bitstamp" => {
+                let trading_pairs = self
+                    .bitstamp_trading_pairs()
+                    .await
+                    .map_err(|err| server::Error::SummaryStreamError(Arc::new(err)))?;
+
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::bitstamp::Bitstamp::new(config.bitstamp_websocket_addr.clone())
+                        .with_channel(if config.bitstamp_diff_order_book {
+                            exchanges::bitstamp::Channel::DiffOrderBook {
+                                rest_url: config.bitstamp_rest_addr.clone(),
+                            }
+                        } else {
+                            exchanges::bitstamp::Channel::OrderBook
+                        })
+                        .with_trading_pairs(trading_pairs),
+                )
+                .instrument(span!(Level::TRACE, "Process bitstamp orderbook", %pair))
+                .await
+            }
+            "bybit" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::bybit::Bybit {
+                        depth: config.bybit_depth,
+                        ping_interval: Duration::from_secs(config.bybit_ping_interval_secs),
+                        ..exchanges::bybit::Bybit::new(config.bybit_websocket_addr.clone())
+                    },
+                )
+                .instrument(span!(Level::TRACE, "Process bybit orderbook", %pair))
+                .await
+            }
+            "coinbase" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::coinbase::Coinbase {
+                        ws_url: config.coinbase_websocket_addr.clone(),
+                        depth: config.summary_size,
+                    },
+                )
+                .instrument(span!(Level::TRACE, "Process coinbase orderbook", %pair))
+                .await
+            }
+            "gemini" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::gemini::Gemini {
+                        ws_url: config.gemini_websocket_addr.clone(),
+                        depth: config.summary_size,
+                    },
+                )
+                .instrument(span!(Level::TRACE, "Process gemini orderbook", %pair))
+                .await
+            }
+            "htx" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::htx::Htx {
+                        ws_url: config.htx_websocket_addr.clone(),
+                        step: exchanges::htx::Step::Step0,
+                    },
+                )
+                .instrument(span!(Level::TRACE, "Process htx orderbook", %pair))
+                .await
+            }
+            "kraken" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::kraken::Kraken {
+                        ws_url: config.kraken_websocket_addr.clone(),
+                        depth: exchanges::kraken::Depth::_10,
+                    },
+                )
+                .instrument(span!(Level::TRACE, "Process kraken orderbook", %pair))
+                .await
+            }
+            "kucoin" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::kucoin::Kucoin {
+                        rest_url: config.kucoin_rest_addr.clone(),
+                    },
+                )
+                .instrument(span!(Level::TRACE, "Process kucoin orderbook", %pair))
+                .await
+            }
+            "okx" => {
+                self.add(
+                    service,
+                    exchange_name,
+                    pair,
+                    feed,
+                    exchanges::okx::Okx {
+                        ws_url: config.okx_websocket_addr.clone(),
+                        channel: exchanges::okx::Channel::Books,
+                    },
+                )
+                .instrument(span!(Level::TRACE, "Process okx orderbook", %pair))
+                .await
+            }
+            _ => Err(server::Error::UnknownExchange(exchange_name)),
+        }
+    }
+
+    /// Add the reconnecting source as `feed` of the pair
+    ///
+    /// Pairs are resolved by the instruments registry on every reconnect, so a pair delisted
+    /// meanwhile fails to reopen instead of reopening the instrument it was first opened by
+    async fn add<G>(
+        &self,
+        service: &server::OrderbookAggregatorService,
+        exchange_name: server::ExchangeName,
+        pair: &Pair,
+        feed: Feed<'_>,
+        source: G,
+    ) -> Result<(), server::Error>
+    where
+        G: order_book::GetOrderBooksStream + Send + Sync + 'static,
+        G::Error: error::Error + Send + Sync + 'static,
+        G::OrderBooksStream: Unpin + Send + 'static,
+    {
+        let source = Reconnecting::new(
+            self.listed(&exchange_name, source),
+            self.config.reconnect_backoff(),
+        );
+        match feed {
+            Feed::Book { quote_currency } => {
+                service
+                    .add_quoted_pair_orderbook_source(exchange_name, pair, quote_currency, source)
+                    .await
+            }
+            Feed::FxRate => {
+                service
+                    .add_fx_rate_source(exchange_name, pair, source)
+                    .await
+            }
+        }
+    }
+
+    /// Keep the fx rate of the pair by the exchange books
+    async fn add_fx_rate_source(
+        &self,
+        service: &server::OrderbookAggregatorService,
+        exchange_name: server::ExchangeName,
+        pair: &Pair,
+    ) -> Result<(), server::Error> {
+        self.add_feed(service, exchange_name, pair, Feed::FxRate)
+            .await
+    }
+}
+
+#[tonic::async_trait]
+impl server::SourceFactory for ExchangeSources {
+    /// Books quoted in a substitute of the pair quote are converted into it
+    async fn add_source(
+        &self,
+        service: &server::OrderbookAggregatorService,
+        exchange_name: server::ExchangeName,
+        pair: &Pair,
+    ) -> Result<(), server::Error> {
+        let quote_currency = self
+            .config
+            .quote_substitutes
+            .quote_currency(&exchange_name, &pair.quote_currency);
+
+        self.add_feed(service, exchange_name, pair, Feed::Book { quote_currency })
+            .await
+    }
 }
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+
+use super::{OrderBook, PriceLevel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// Order book maintained locally from a snapshot and a stream of deltas
+///
+/// Used by connectors whose exchanges publish incremental updates
+/// instead of complete books on every push
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocalOrderBook {
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl From<OrderBook> for LocalOrderBook {
+    fn from(order_book: OrderBook) -> Self {
+        let mut self_ = Self::default();
+        self_.apply_order_book(order_book);
+        self_
+    }
+}
+
+impl LocalOrderBook {
+    /// Set the quantity of the level, a zero quantity removes the level
+    pub fn apply(&mut self, side: Side, PriceLevel { price, quantity }: PriceLevel) {
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+
+        if quantity.is_zero() {
+            levels.remove(&price);
+        } else {
+            levels.insert(price, quantity);
+        }
+    }
+
+    /// Quantity of the level, if there is one
+    pub fn quantity(&self, side: Side, price: &Decimal) -> Option<Decimal> {
+        match side {
+            Side::Bid => self.bids.get(price).copied(),
+            Side::Ask => self.asks.get(price).copied(),
+        }
+    }
+
+    pub fn apply_order_book(&mut self, OrderBook { bids, asks }: OrderBook) {
+        bids.into_iter()
+            .for_each(|level| self.apply(Side::Bid, level));
+        asks.into_iter()
+            .for_each(|level| self.apply(Side::Ask, level));
+    }
+
+    /// Keep at most `depth` best levels per side, for exchanges that don't
+    /// remove levels falling out of the subscribed depth
+    pub fn truncate(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            self.bids.pop_first();
+        }
+        while self.asks.len() > depth {
+            self.asks.pop_last();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// Bids from the highest price, asks from the lowest one
+    pub fn to_order_book(&self) -> OrderBook {
+        self.top(usize::MAX)
+    }
+
+    /// Same as [`LocalOrderBook::to_order_book`], but at most `depth` levels per side
+    pub fn top(&self, depth: usize) -> OrderBook {
+        let into_level = |(price, quantity): (&Decimal, &Decimal)| PriceLevel {
+            price: *price,
+            quantity: *quantity,
+        };
+
+        OrderBook {
+            bids: self.bids.iter().rev().take(depth).map(into_level).collect(),
+            asks: self.asks.iter().take(depth).map(into_level).collect(),
+        }
+    }
+}
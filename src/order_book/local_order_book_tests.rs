@@ -0,0 +1,58 @@
+use super::{LocalOrderBook, OrderBook, Side};
+use crate::exchanges::fixtures::level;
+
+#[test]
+fn test_local_order_book_sorting() {
+    let book = LocalOrderBook::from(OrderBook {
+        bids: vec![level("90", "1"), level("100", "2"), level("95", "3")],
+        asks: vec![level("120", "1"), level("110", "2"), level("115", "3")],
+    });
+
+    let order_book = book.to_order_book();
+    assert_eq!(
+        order_book.bids,
+        vec![level("100", "2"), level("95", "3"), level("90", "1")]
+    );
+    assert_eq!(
+        order_book.asks,
+        vec![level("110", "2"), level("115", "3"), level("120", "1")]
+    );
+
+    let top = book.top(2);
+    assert_eq!(top.bids, vec![level("100", "2"), level("95", "3")]);
+    assert_eq!(top.asks, vec![level("110", "2"), level("115", "3")]);
+}
+
+#[test]
+fn test_local_order_book_apply() {
+    let mut book = LocalOrderBook::from(OrderBook {
+        bids: vec![level("100", "1"), level("90", "1")],
+        asks: vec![level("110", "1")],
+    });
+
+    book.apply(Side::Bid, level("100.00", "5"));
+    book.apply(Side::Bid, level("90", "0"));
+    book.apply(Side::Ask, level("105", "2"));
+    book.apply(Side::Ask, level("130", "0"));
+
+    let order_book = book.to_order_book();
+    assert_eq!(order_book.bids, vec![level("100", "5")]);
+    assert_eq!(order_book.asks, vec![level("105", "2"), level("110", "1")]);
+
+    book.clear();
+    assert!(book.is_empty());
+}
+
+#[test]
+fn test_local_order_book_truncate() {
+    let mut book = LocalOrderBook::from(OrderBook {
+        bids: vec![level("100", "1"), level("95", "2"), level("90", "3")],
+        asks: vec![level("110", "1"), level("115", "2"), level("120", "3")],
+    });
+
+    book.truncate(2);
+
+    let order_book = book.to_order_book();
+    assert_eq!(order_book.bids, vec![level("100", "1"), level("95", "2")]);
+    assert_eq!(order_book.asks, vec![level("110", "1"), level("115", "2")]);
+}
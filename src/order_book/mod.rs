@@ -1,8 +1,13 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
 use futures_util::Stream;
 use rust_decimal::Decimal;
 use serde::Deserialize;
 
-use crate::proto;
+use crate::{exchanges::reconnect::ReconnectStats, instruments::Instrument, proto};
+
+mod local_order_book;
+pub use local_order_book::{LocalOrderBook, Side};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PriceLevel {
@@ -29,8 +34,27 @@ impl PriceLevel {
             exchange: exchange.to_string(),
             amount: Some(self.quantity.into()),
             price: Some(self.price.into()),
+            ..Default::default()
         }
     }
+
+    /// Level of a book quoted in `quote_currency`, with the price converted by `rate`,
+    /// `None` if the converted price overflows
+    pub fn to_converted_proto(
+        &self,
+        exchange: &str,
+        quote_currency: &str,
+        rate: Decimal,
+    ) -> Option<proto::PriceLevel> {
+        Some(proto::PriceLevel {
+            exchange: exchange.to_string(),
+            amount: Some(self.quantity.into()),
+            price: Some(self.price.checked_mul(rate)?.into()),
+            quote_currency: quote_currency.to_string(),
+            conversion_rate: Some(rate.into()),
+            ..Default::default()
+        })
+    }
 }
 
 #[cfg(test)]
@@ -42,6 +66,45 @@ pub struct OrderBook {
     pub asks: Vec<PriceLevel>,
 }
 
+/// Traded pair of currencies, both are kept in lowercase
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Pair {
+    pub base_currency: String,
+    pub quote_currency: String,
+}
+impl Pair {
+    pub fn new(base_currency: &str, quote_currency: &str) -> Self {
+        Self {
+            base_currency: base_currency.to_lowercase(),
+            quote_currency: quote_currency.to_lowercase(),
+        }
+    }
+}
+impl fmt::Display for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base_currency, self.quote_currency)
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Pair {0:?} must be formatted as `base/quote`")]
+pub struct ParsePairError(String);
+
+impl FromStr for Pair {
+    type Err = ParsePairError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once('/') {
+            Some((base_currency, quote_currency))
+                if !base_currency.is_empty() && !quote_currency.is_empty() =>
+            {
+                Ok(Self::new(base_currency, quote_currency))
+            }
+            _ => Err(ParsePairError(s.to_owned())),
+        }
+    }
+}
+
 #[tonic::async_trait]
 pub trait GetOrderBooksStream {
     type Error;
@@ -52,7 +115,26 @@ pub trait GetOrderBooksStream {
         base_currency: &str,
         quote_currency: &str,
     ) -> Result<Self::OrderBooksStream, Self::Error>;
+
+    /// Stream of the instrument listed by the exchange, opened by its native symbol
+    ///
+    /// Sources that don't subscribe by symbol are opened with the native assets of the instrument
+    async fn get_instrument_order_books_stream(
+        &self,
+        instrument: &Instrument,
+    ) -> Result<Self::OrderBooksStream, Self::Error> {
+        self.get_order_books_stream(&instrument.base_currency, &instrument.quote_currency)
+            .await
+    }
+
+    /// Counters of reconnects of the source streams, if the source reconnects them
+    fn reconnect_stats(&self) -> Option<Arc<ReconnectStats>> {
+        None
+    }
 }
 
 #[cfg(test)]
 mod order_book_test;
+
+#[cfg(test)]
+mod local_order_book_tests;
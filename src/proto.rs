@@ -1,8 +1,10 @@
 use std::cmp;
 
+use crate::order_book::Side;
+
 tonic::include_proto!("orderbook");
 
-const DEFAULT_DECIMAL_SCALE: u32 = 25;
+const LEGACY_DECIMAL_SCALE: u32 = 25;
 
 impl PartialOrd for Decimal {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
@@ -32,42 +34,159 @@ impl Ord for PriceLevel {
 
 impl From<&Decimal> for rust_decimal::Decimal {
     fn from(value: &Decimal) -> Self {
-        Self::from_parts(value.lo, value.mid, value.hi, false, DEFAULT_DECIMAL_SCALE)
+        match value.encoding() {
+            DecimalEncoding::LegacyScale25 => {
+                Self::from_parts(value.lo, value.mid, value.hi, false, LEGACY_DECIMAL_SCALE)
+            }
+            DecimalEncoding::SignedScaled => {
+                Self::from_parts(value.lo, value.mid, value.hi, value.negative, value.scale)
+            }
+        }
     }
 }
 impl From<rust_decimal::Decimal> for Decimal {
-    fn from(mut value: rust_decimal::Decimal) -> Self {
-        value.rescale(DEFAULT_DECIMAL_SCALE);
-        // We can bound flags by a bitwise mask to correspond to:
-        //   Bits 0-15: unused
-        //   Bits 16-23: Contains "e", a value between 0-28 that indicates the scale
-        //   Bits 24-30: unused
-        //   Bit 31: the sign of the Decimal value, 0 meaning positive and 1 meaning negative.
-        let bytes = value.serialize();
+    fn from(value: rust_decimal::Decimal) -> Self {
+        Self::encode(value, DecimalEncoding::SignedScaled)
+    }
+}
+
+impl Decimal {
+    /// Values not fitting the legacy encoding are encoded signed scaled
+    pub fn encode(mut value: rust_decimal::Decimal, encoding: DecimalEncoding) -> Self {
+        let view = value.to_string();
+        let (negative, scale) = match encoding {
+            DecimalEncoding::LegacyScale25 => {
+                let original = value;
+                value.rescale(LEGACY_DECIMAL_SCALE);
+                // `rescale` keeps a lower scale if the mantissa would overflow
+                if value.scale() != LEGACY_DECIMAL_SCALE {
+                    return Self::encode(original, DecimalEncoding::SignedScaled);
+                }
+                (false, 0)
+            }
+            DecimalEncoding::SignedScaled => (value.is_sign_negative(), value.scale()),
+        };
+
+        let mantissa = value.mantissa().unsigned_abs();
         Self {
-            lo: (bytes[4] as u32)
-                | (bytes[5] as u32) << 8
-                | (bytes[6] as u32) << 16
-                | (bytes[7] as u32) << 24,
-            mid: (bytes[8] as u32)
-                | (bytes[9] as u32) << 8
-                | (bytes[10] as u32) << 16
-                | (bytes[11] as u32) << 24,
-            hi: (bytes[12] as u32)
-                | (bytes[13] as u32) << 8
-                | (bytes[14] as u32) << 16
-                | (bytes[15] as u32) << 24,
-            view: value.to_string(),
+            lo: mantissa as u32,
+            mid: (mantissa >> 32) as u32,
+            hi: (mantissa >> 64) as u32,
+            view,
+            encoding: encoding as i32,
+            negative,
+            scale,
+        }
+    }
+
+    pub fn to_encoding(&self, encoding: DecimalEncoding) -> Self {
+        if self.encoding() == encoding {
+            self.clone()
+        } else {
+            Self::encode(rust_decimal::Decimal::from(self), encoding)
         }
     }
 }
 
+impl PriceLevel {
+    pub fn to_encoding(&self, encoding: DecimalEncoding) -> Self {
+        Self {
+            exchange: self.exchange.clone(),
+            price: self.price.as_ref().map(|price| price.to_encoding(encoding)),
+            amount: self
+                .amount
+                .as_ref()
+                .map(|amount| amount.to_encoding(encoding)),
+            quote_currency: self.quote_currency.clone(),
+            conversion_rate: self
+                .conversion_rate
+                .as_ref()
+                .map(|rate| rate.to_encoding(encoding)),
+            raw_price: self
+                .raw_price
+                .as_ref()
+                .map(|raw_price| raw_price.to_encoding(encoding)),
+            breakdown: self
+                .breakdown
+                .iter()
+                .map(|level| level.to_encoding(encoding))
+                .collect(),
+        }
+    }
+
+    /// Level of all exchanges at the price, so far of this one only
+    pub fn consolidated(self) -> Self {
+        Self {
+            price: self.price.clone(),
+            amount: self.amount.clone(),
+            breakdown: vec![self],
+            ..Self::default()
+        }
+    }
+
+    /// Add the level of another exchange at the same price into the consolidated one
+    pub fn consolidate(&mut self, level: Self) {
+        let amount = |level: &Self| {
+            level
+                .amount
+                .as_ref()
+                .map(rust_decimal::Decimal::from)
+                .unwrap_or_default()
+        };
+        self.amount = Some((amount(self) + amount(&level)).into());
+        self.breakdown.push(level);
+    }
+
+    /// Whether levels are at the same price, regardless of the scale
+    pub fn same_price(&self, other: &Self) -> bool {
+        self.price.partial_cmp(&other.price) == Some(cmp::Ordering::Equal)
+    }
+
+    /// Level of `exchanges` only, a consolidated one keeps levels of them in the breakdown
+    fn of_exchanges(&self, exchanges: &[String]) -> Option<Self> {
+        if self.breakdown.is_empty() {
+            return exchanges.contains(&self.exchange).then(|| self.clone());
+        }
+
+        self.breakdown
+            .iter()
+            .filter(|level| exchanges.contains(&level.exchange))
+            .cloned()
+            .fold(
+                None,
+                |consolidated: Option<Self>, level| match consolidated {
+                    Some(mut consolidated) => {
+                        consolidated.consolidate(level);
+                        Some(consolidated)
+                    }
+                    None => Some(level.consolidated()),
+                },
+            )
+    }
+
+    /// Level priced with the taker `fee`, what is paid for asks and received for bids,
+    /// the raw price is kept in `raw_price`
+    pub fn with_taker_fee(mut self, side: Side, fee: rust_decimal::Decimal) -> Self {
+        let factor = match side {
+            Side::Ask => rust_decimal::Decimal::ONE + fee,
+            Side::Bid => rust_decimal::Decimal::ONE - fee,
+        };
+        self.raw_price = self.price.take();
+        self.price = self
+            .raw_price
+            .as_ref()
+            .map(|raw_price| (rust_decimal::Decimal::from(raw_price) * factor).into());
+        self
+    }
+}
+
 impl Summary {
     pub fn new(asks: Vec<PriceLevel>, bids: Vec<PriceLevel>) -> Self {
         let mut self_ = Self {
             asks,
             bids,
             spread: None,
+            lagged: 0,
         };
 
         self_.spread = self_.calculate_spread();
@@ -87,6 +206,38 @@ impl Summary {
             })
     }
 
+    /// Keep at most `depth` levels per side of `exchanges` only (of all, if empty),
+    /// consolidated levels are narrowed to `exchanges`
+    pub fn filtered(&self, depth: usize, exchanges: &[String]) -> Self {
+        let filter = |levels: &[PriceLevel]| {
+            levels
+                .iter()
+                .filter_map(|l| {
+                    if exchanges.is_empty() {
+                        Some(l.clone())
+                    } else {
+                        l.of_exchanges(exchanges)
+                    }
+                })
+                .take(depth)
+                .collect()
+        };
+
+        Self::new(filter(&self.asks), filter(&self.bids))
+    }
+
+    pub fn to_encoding(&self, encoding: DecimalEncoding) -> Self {
+        Self {
+            spread: self
+                .spread
+                .as_ref()
+                .map(|spread| spread.to_encoding(encoding)),
+            bids: self.bids.iter().map(|l| l.to_encoding(encoding)).collect(),
+            asks: self.asks.iter().map(|l| l.to_encoding(encoding)).collect(),
+            lagged: self.lagged,
+        }
+    }
+
     pub fn best_bid(&self) -> Option<&PriceLevel> {
         self.bids
             .iter()
@@ -101,6 +252,8 @@ impl Summary {
 
 #[cfg(test)]
 mod test {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -117,17 +270,20 @@ mod test {
         }
 
         let orderbook = Summary {
+            lagged: 0,
             spread: Some(decimal!("0.0000120")),
             bids: vec![
                 PriceLevel {
                     exchange: "unknown".to_owned(),
                     price: Some(decimal!("0.03562200")),
                     amount: Some(decimal!("7.90700000")),
+                    ..Default::default()
                 },
                 PriceLevel {
                     exchange: "unknown".to_owned(),
                     price: Some(decimal!("0.03561700")),
                     amount: Some(decimal!("12.20300000")),
+                    ..Default::default()
                 },
             ],
             asks: vec![
@@ -135,11 +291,13 @@ mod test {
                     exchange: "unknown".to_owned(),
                     price: Some(decimal!("0.03563400")),
                     amount: Some(decimal!("6.10000000")),
+                    ..Default::default()
                 },
                 PriceLevel {
                     exchange: "unkown".to_owned(),
                     price: Some(decimal!("0.03564400")),
                     amount: Some(decimal!("1.00000000")),
+                    ..Default::default()
                 },
             ],
         };
@@ -155,7 +313,85 @@ mod test {
             _ => decimal!("0"),
         };
 
-        assert_eq!(spread, decimal!("0.0000120"));
+        assert_eq!(
+            decimal!(spread),
+            rust_decimal::Decimal::from_str("0.0000120").unwrap()
+        );
         assert_eq!(spread, orderbook.calculate_spread().unwrap());
     }
+
+    #[test]
+    fn test_crossed_book_spread() {
+        use std::str::FromStr;
+
+        let level = |price: &str| PriceLevel {
+            exchange: "unknown".to_owned(),
+            price: Some(rust_decimal::Decimal::from_str(price).unwrap().into()),
+            amount: Some(rust_decimal::Decimal::ONE.into()),
+            ..Default::default()
+        };
+
+        let summary = Summary::new(vec![level("99.5")], vec![level("100.25")]);
+
+        assert_eq!(
+            rust_decimal::Decimal::from(summary.spread.as_ref().unwrap()),
+            rust_decimal::Decimal::from_str("-0.75").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_legacy_encoding() {
+        use std::str::FromStr;
+
+        let value = rust_decimal::Decimal::from_str("7.5").unwrap();
+
+        // 7.5 * 10^25, as sent before encodings were introduced
+        let legacy = Decimal {
+            lo: 0x2B000000,
+            mid: 0x2596099E,
+            hi: 0x003E09DE,
+            view: "7.5000000000000000000000000".to_owned(),
+            encoding: 0,
+            negative: false,
+            scale: 0,
+        };
+        assert_eq!(rust_decimal::Decimal::from(&legacy), value);
+
+        let encoded = Decimal::from(value).to_encoding(DecimalEncoding::LegacyScale25);
+        assert_eq!(encoded.encoding(), DecimalEncoding::LegacyScale25);
+        assert_eq!(
+            (encoded.lo, encoded.mid, encoded.hi),
+            (legacy.lo, legacy.mid, legacy.hi)
+        );
+        assert_eq!(rust_decimal::Decimal::from(&encoded), value);
+
+        let encoded = encoded.to_encoding(DecimalEncoding::SignedScaled);
+        assert_eq!(encoded.encoding(), DecimalEncoding::SignedScaled);
+        assert_eq!(rust_decimal::Decimal::from(&encoded), value);
+
+        // Too large for the mantissa at scale 25
+        let value = rust_decimal::Decimal::from_str("30000.5").unwrap();
+        let encoded = Decimal::encode(value, DecimalEncoding::LegacyScale25);
+        assert_eq!(encoded.encoding(), DecimalEncoding::SignedScaled);
+        assert_eq!(rust_decimal::Decimal::from(&encoded), value);
+        assert_eq!(encoded.view, "30000.5");
+    }
+
+    proptest! {
+        #[test]
+        fn test_decimal_round_trip(
+            lo in any::<u32>(),
+            mid in any::<u32>(),
+            hi in any::<u32>(),
+            negative in any::<bool>(),
+            scale in 0u32..=28,
+        ) {
+            let value = rust_decimal::Decimal::from_parts(lo, mid, hi, negative, scale);
+            let encoded = Decimal::from(value);
+
+            let decoded = rust_decimal::Decimal::from(&encoded);
+            prop_assert_eq!(decoded.serialize(), value.serialize());
+            prop_assert_eq!(encoded.view, value.to_string());
+        }
+    }
 }
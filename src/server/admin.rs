@@ -0,0 +1,102 @@
+use std::sync::atomic::Ordering;
+
+use tonic::{Request, Response, Status};
+
+use super::{Error, ExchangeName, OrderbookAggregatorService};
+use crate::{
+    order_book::Pair,
+    proto::{orderbook_admin_server::OrderbookAdmin, Empty, Source, Sources},
+};
+
+/// Creates sources of orderbooks by exchange name, so that they can be added at runtime
+#[tonic::async_trait]
+pub trait SourceFactory: Send + Sync + 'static {
+    /// Add source of the exchange orderbooks of the pair into the aggregator,
+    /// [`Error::UnknownExchange`] if there is no connector for the exchange
+    async fn add_source(
+        &self,
+        aggregator: &OrderbookAggregatorService,
+        exchange_name: ExchangeName,
+        pair: &Pair,
+    ) -> Result<(), Error>;
+}
+
+/// Service for managing sources of the running [`OrderbookAggregatorService`]
+pub struct AdminService<F> {
+    aggregator: OrderbookAggregatorService,
+    source_factory: F,
+}
+impl<F> AdminService<F> {
+    pub fn new(aggregator: OrderbookAggregatorService, source_factory: F) -> Self {
+        Self {
+            aggregator,
+            source_factory,
+        }
+    }
+}
+
+fn parse_source(source: Source) -> (ExchangeName, Pair) {
+    let Source {
+        exchange,
+        base_currency,
+        quote_currency,
+        ..
+    } = source;
+
+    (
+        exchange.to_lowercase(),
+        Pair::new(&base_currency, &quote_currency),
+    )
+}
+
+#[tonic::async_trait]
+impl<F: SourceFactory> OrderbookAdmin for AdminService<F> {
+    async fn add_source(&self, request: Request<Source>) -> Result<Response<Empty>, Status> {
+        let (exchange_name, pair) = parse_source(request.into_inner());
+
+        self.source_factory
+            .add_source(&self.aggregator, exchange_name, &pair)
+            .await?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn remove_source(&self, request: Request<Source>) -> Result<Response<Empty>, Status> {
+        let (exchange_name, pair) = parse_source(request.into_inner());
+
+        self.aggregator
+            .remove_orderbook_source(&exchange_name, &pair)
+            .await?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn list_sources(&self, _request: Request<Empty>) -> Result<Response<Sources>, Status> {
+        Ok(Response::new(Sources {
+            sources: self
+                .aggregator
+                .sources()
+                .into_iter()
+                .map(|(exchange, pair)| {
+                    let stats = self.aggregator.reconnect_stats(&exchange, &pair);
+                    let (reconnects, failed_reconnect_attempts) = stats
+                        .map(|stats| {
+                            (
+                                stats.reconnects.load(Ordering::Relaxed),
+                                stats.failed_attempts.load(Ordering::Relaxed),
+                            )
+                        })
+                        .unwrap_or_default();
+
+                    Source {
+                        exchange,
+                        base_currency: pair.base_currency,
+                        quote_currency: pair.quote_currency,
+                        reconnects,
+                        failed_reconnect_attempts,
+                    }
+                })
+                .collect(),
+        }))
+    }
+}
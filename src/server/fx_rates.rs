@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use rust_decimal::Decimal;
+
+use crate::order_book::{OrderBook, Pair};
+
+/// Rates between currencies, set by config or kept live by books of fx pairs
+///
+/// Clones share rates, so a rate updated by a source is used by mergers of all pairs
+#[derive(Debug, Clone, Default)]
+pub struct FxRates {
+    /// Price of one base currency in the quote currency, by pair
+    rates: Arc<RwLock<HashMap<Pair, Decimal>>>,
+}
+
+impl FxRates {
+    /// Set the price of one `pair` base currency in its quote currency
+    pub fn set(&self, pair: Pair, rate: Decimal) {
+        self.rates.write().unwrap().insert(pair, rate);
+    }
+
+    /// Set the rate of the pair to the mid price of its book, books without both sides are skipped
+    pub fn set_mid_price(&self, pair: &Pair, order_book: &OrderBook) -> Option<Decimal> {
+        let best_bid = order_book.bids.first()?.price;
+        let best_ask = order_book.asks.first()?.price;
+        let mid_price = (best_bid + best_ask) / Decimal::TWO;
+
+        (!mid_price.is_zero()).then(|| {
+            self.set(pair.clone(), mid_price);
+            mid_price
+        })
+    }
+
+    /// Multiplier converting prices in `from` currency into `to` currency, by the direct
+    /// or the inverse pair
+    pub fn rate(&self, from: &str, to: &str) -> Option<Decimal> {
+        if from == to {
+            return Some(Decimal::ONE);
+        }
+
+        let rates = self.rates.read().unwrap();
+        rates.get(&Pair::new(from, to)).copied().or_else(|| {
+            rates
+                .get(&Pair::new(to, from))
+                .and_then(|rate| Decimal::ONE.checked_div(*rate))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::order_book::PriceLevel;
+
+    fn level(price: &str) -> PriceLevel {
+        PriceLevel {
+            price: Decimal::from_str(price).unwrap(),
+            quantity: Decimal::ONE,
+        }
+    }
+
+    #[test]
+    fn test_rate() {
+        let fx_rates = FxRates::default();
+        fx_rates.set(Pair::new("usdt", "usd"), Decimal::from_str("0.8").unwrap());
+
+        assert_eq!(fx_rates.rate("usdt", "usdt"), Some(Decimal::ONE));
+        assert_eq!(
+            fx_rates.rate("usdt", "usd"),
+            Some(Decimal::from_str("0.8").unwrap())
+        );
+        assert_eq!(
+            fx_rates.rate("usd", "usdt"),
+            Some(Decimal::from_str("1.25").unwrap())
+        );
+        assert_eq!(fx_rates.rate("usdc", "usdt"), None);
+
+        fx_rates.set(Pair::new("usdc", "usd"), Decimal::ZERO);
+        assert_eq!(fx_rates.rate("usd", "usdc"), None);
+
+        let usdt_usd = OrderBook {
+            bids: vec![level("0.9998"), level("0.9997")],
+            asks: vec![level("1.0002")],
+        };
+        assert_eq!(
+            fx_rates.set_mid_price(&Pair::new("usdt", "usd"), &usdt_usd),
+            Some(Decimal::ONE)
+        );
+        assert_eq!(fx_rates.rate("usd", "usdt"), Some(Decimal::ONE));
+
+        let one_sided = OrderBook {
+            bids: vec![level("0.9")],
+            asks: vec![],
+        };
+        assert_eq!(
+            fx_rates.set_mid_price(&Pair::new("usdt", "usd"), &one_sided),
+            None
+        );
+        assert_eq!(fx_rates.rate("usdt", "usd"), Some(Decimal::ONE));
+    }
+}
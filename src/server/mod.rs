@@ -1,22 +1,60 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use order_book_merger::{ExchangeName, OrderBookMerger};
-use tokio::sync::{broadcast, Mutex};
+use futures_util::future::Either;
+use itertools::Itertools;
+use order_book_merger::OrderBookMerger;
+use tokio::{
+    sync::{broadcast, watch, Mutex},
+    task::{AbortHandle, JoinSet},
+};
 use tokio_stream::{
-    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream},
     Stream, StreamExt,
 };
 use tonic::{Request, Response, Status};
 use tracing::*;
 
-use crate::proto::{orderbook_aggregator_server::OrderbookAggregator, Empty, Summary};
+use crate::{
+    exchanges::reconnect::ReconnectStats,
+    order_book::Pair,
+    proto::{
+        orderbook_aggregator_server::OrderbookAggregator, BookSummaryRequest, DecimalEncoding,
+        Summary,
+    },
+};
 
+mod admin;
+mod fx_rates;
 mod order_book_merger;
 
+pub use admin::{AdminService, SourceFactory};
+pub use fx_rates::FxRates;
+pub use order_book_merger::ExchangeName;
+
 #[derive(Debug, thiserror::Error, Clone)]
 pub enum Error {
     #[error("")]
     SummaryStreamError(Arc<dyn std::error::Error + Send + Sync>),
+    #[error("Source {exchange_name} of {pair} is already added")]
+    SourceExists {
+        exchange_name: ExchangeName,
+        pair: Pair,
+    },
+    #[error("Source {exchange_name} of {pair} is not found")]
+    SourceNotFound {
+        exchange_name: ExchangeName,
+        pair: Pair,
+    },
+    #[error("Exchange {0} is not supported")]
+    UnknownExchange(ExchangeName),
 }
 
 impl From<Error> for tonic::Status {
@@ -24,65 +62,537 @@ impl From<Error> for tonic::Status {
         // WARN A more detailed status can and
         // should be made depending on the error,
         // but let's keep it simple
-        tonic::Status::internal(value.to_string())
+        match value {
+            Error::SourceExists { .. } => tonic::Status::already_exists(value.to_string()),
+            Error::SourceNotFound { .. } => tonic::Status::not_found(value.to_string()),
+            Error::UnknownExchange(_) => tonic::Status::invalid_argument(value.to_string()),
+            Error::SummaryStreamError(_) => tonic::Status::internal(value.to_string()),
+        }
     }
 }
 
-pub type OrderbookSender = broadcast::Sender<Result<Summary, Error>>;
+/// Summary numbered in the order of publication
+#[derive(Debug, Clone)]
+pub struct SummaryUpdate {
+    pub sequence: u64,
+    pub summary: Summary,
+    /// Levels of each exchange within the summary size, filtered by subscribers of some exchanges
+    pub exchanges_summary: Arc<Summary>,
+}
 
-/// Structure for processing and order book providing via gprc
-pub struct OrderbookAggregatorService {
+/// Summary sent to a particular subscriber
+#[derive(Debug, Clone)]
+struct SubscriberUpdate {
+    summary: Summary,
+    exchanges_summary: Arc<Summary>,
+    /// Number of summaries skipped by the subscriber since it subscribed
+    lagged: u64,
+}
+
+pub type OrderbookSender = broadcast::Sender<Result<SummaryUpdate, Error>>;
+
+/// Per subscriber view of the merged summary
+#[derive(Debug, Default)]
+struct SummaryFilter {
+    depth: Option<usize>,
+    exchanges: Vec<ExchangeName>,
+    summary_size: usize,
+}
+impl SummaryFilter {
+    /// Levels of other exchanges are filtered out before the summary is cut to the depth,
+    /// so the subscriber gets as many levels as the exchanges have
+    fn apply(&self, summary: Summary, exchanges_summary: &Summary) -> Summary {
+        match self {
+            Self {
+                depth: None,
+                exchanges,
+                ..
+            } if exchanges.is_empty() => summary,
+            Self {
+                depth: Some(depth),
+                exchanges,
+                ..
+            } if exchanges.is_empty() => summary.filtered(*depth, exchanges),
+            Self {
+                depth,
+                exchanges,
+                summary_size,
+            } => exchanges_summary.filtered(depth.unwrap_or(*summary_size), exchanges),
+        }
+    }
+}
+
+/// Merged orderbook of one pair with its subscribers
+#[derive(Clone)]
+struct PairAggregator {
     /// The entry part of the broadcast channel that is used to send the orderbook to all subscribers
     orderbook_sender: OrderbookSender,
+    /// The last published summary, sent first to every new subscriber
+    latest_summary: Arc<watch::Sender<Option<SummaryUpdate>>>,
+    /// Number of levels per side of the published summary
+    summary_size: usize,
     /// A structure that stores the orderbook from all exchanges and makes it available externally
     /// NOTE: Started playing with lock-free maps, but didn't get carried away so as not to waste time.
     ///       For these conditions rwlock is sufficient.
     merged_summary: Arc<Mutex<OrderBookMerger>>,
+}
+impl PairAggregator {
+    fn new(merger: OrderBookMerger) -> Self {
+        Self {
+            orderbook_sender: broadcast::channel(10).0,
+            latest_summary: Arc::new(watch::channel(None).0),
+            summary_size: merger.summary_size(),
+            merged_summary: Arc::new(Mutex::new(merger)),
+        }
+    }
+
+    /// Publish levels of each exchange within the summary size, cut to the summary size
+    /// for subscribers of all exchanges
+    ///
+    /// Must be called under the `merged_summary` lock, so that sequence follows the merge order
+    fn publish(&self, exchanges_summary: Summary) {
+        let update = SummaryUpdate {
+            sequence: self
+                .latest_summary
+                .borrow()
+                .as_ref()
+                .map_or(0, |latest| latest.sequence + 1),
+            summary: exchanges_summary.filtered(self.summary_size, &[]),
+            exchanges_summary: Arc::new(exchanges_summary),
+        };
+
+        // The latest summary is replaced before sending, so a subscriber that
+        // has seen it either receives the same update via channel or none at all
+        self.latest_summary.send_replace(Some(update.clone()));
+
+        match self.orderbook_sender.send(Ok(update)) {
+            Ok(receiver_count) => info!("Send summary to {receiver_count} receiver"),
+            Err(_) => info!("No subscribers"),
+        }
+    }
+
+    /// Stream of the latest summary followed by all later ones
+    fn updates(
+        &self,
+    ) -> impl Stream<Item = Result<Result<SummaryUpdate, Error>, BroadcastStreamRecvError>> {
+        // Subscribe before reading the latest summary, so that nothing is missed between them
+        let receiver = self.orderbook_sender.subscribe();
+        let latest = self.latest_summary.borrow().clone();
+        let latest_sequence = latest.as_ref().map(|latest| latest.sequence);
+
+        tokio_stream::iter(latest.map(|latest| Ok(Ok(latest)))).chain(
+            BroadcastStream::new(receiver).filter(move |update| match (update, latest_sequence) {
+                (Ok(Ok(update)), Some(latest_sequence)) => update.sequence > latest_sequence,
+                _ => true,
+            }),
+        )
+    }
 
-    base_currency: String,
-    quote_currency: String,
+    /// Stream of summaries for one subscriber, each with the number of summaries it skipped so far
+    ///
+    /// A lagged subscriber is fast-forwarded to the latest summary, since every summary is
+    /// a full snapshot. With `conflate` the summaries are never queued, only the latest is sent
+    fn subscribe(&self, conflate: bool) -> impl Stream<Item = Result<SubscriberUpdate, Error>> {
+        let updates = if conflate {
+            Either::Left(
+                WatchStream::new(self.latest_summary.subscribe())
+                    .filter_map(|latest| latest.map(Ok)),
+            )
+        } else {
+            let latest_summary = self.latest_summary.clone();
+            Either::Right(self.updates().filter_map(move |update| match update {
+                Ok(update) => Some(update),
+                Err(BroadcastStreamRecvError::Lagged(lagged)) => {
+                    warn!("Lagged {lagged} messages, fast-forward to the latest summary");
+                    latest_summary.borrow().clone().map(Ok)
+                }
+            }))
+        };
+
+        let mut last_sequence = None;
+        let mut lagged = 0;
+        updates.filter_map(move |update| {
+            let SummaryUpdate {
+                sequence,
+                summary,
+                exchanges_summary,
+            } = match update {
+                Ok(update) => update,
+                Err(err) => return Some(Err(err)),
+            };
+
+            if let Some(last_sequence) = last_sequence {
+                // Updates older than the one fast-forwarded to are still queued in the channel
+                if sequence <= last_sequence {
+                    return None;
+                }
+                lagged += sequence - last_sequence - 1;
+            }
+            last_sequence = Some(sequence);
+
+            Some(Ok(SubscriberUpdate {
+                summary,
+                exchanges_summary,
+                lagged,
+            }))
+        })
+    }
+}
 
-    orderbook_source_tasks: tokio::task::JoinSet<()>,
+/// Task handling orderbooks of an exchange source of a pair
+struct SourceTask {
+    id: u64,
+    abort_handle: AbortHandle,
+    reconnect_stats: Option<Arc<ReconnectStats>>,
+}
+
+/// Structure for processing and order book providing via gprc
+///
+/// Clones share pairs and sources, so sources can be managed while the service is running
+#[derive(Clone)]
+pub struct OrderbookAggregatorService {
+    pairs: Arc<RwLock<HashMap<Pair, PairAggregator>>>,
+    /// Pair served to requests without currencies
+    default_pair: Pair,
+    summary_size: usize,
+    staleness_threshold: Option<Duration>,
+    /// Tasks evicting stale books of each pair, replaced when the threshold is changed
+    evictions: Arc<std::sync::Mutex<HashMap<Pair, AbortHandle>>>,
+    /// Rates converting books quoted in other currencies than the served pair
+    fx_rates: FxRates,
+    /// Taker fees by exchange, summaries are fee-adjusted if set
+    taker_fees: Option<HashMap<ExchangeName, rust_decimal::Decimal>>,
+    /// Levels of exchanges at the same price are combined into one
+    consolidated: bool,
+
+    orderbook_source_tasks: Arc<std::sync::Mutex<JoinSet<()>>>,
+    /// Tasks of `orderbook_source_tasks` handling each exchange orderbooks of a pair
+    sources: Arc<std::sync::Mutex<HashMap<(ExchangeName, Pair), SourceTask>>>,
+    /// Identifies tasks of `sources`, so that a finished task removes its own entry only
+    next_source_id: Arc<AtomicU64>,
 }
 impl OrderbookAggregatorService {
     pub fn new(base_currency: &str, quote_currency: &str, summary_size: usize) -> Self {
-        Self {
-            orderbook_sender: broadcast::channel(10).0,
-            base_currency: base_currency.to_string(),
-            quote_currency: quote_currency.to_string(),
-            merged_summary: Arc::new(Mutex::new(OrderBookMerger::new(summary_size))),
-            orderbook_source_tasks: tokio::task::JoinSet::default(),
+        let self_ = Self {
+            pairs: Arc::default(),
+            default_pair: Pair::new(base_currency, quote_currency),
+            summary_size,
+            staleness_threshold: None,
+            evictions: Arc::default(),
+            fx_rates: FxRates::default(),
+            taker_fees: None,
+            consolidated: false,
+            orderbook_source_tasks: Arc::default(),
+            sources: Arc::default(),
+            next_source_id: Arc::default(),
+        };
+        self_.add_pair(self_.default_pair.clone());
+        self_
+    }
+
+    /// Spawn the task into `orderbook_source_tasks`, collecting the finished ones
+    fn spawn_task(&self, task: impl Future<Output = ()> + Send + 'static) -> AbortHandle {
+        let mut tasks = self.orderbook_source_tasks.lock().unwrap();
+        while let Some(finished) = tasks.try_join_next() {
+            match finished {
+                Err(err) if !err.is_cancelled() => error!("Orderbook task failed: {err:?}"),
+                _ => {}
+            }
         }
+        tasks.spawn(task)
     }
 
-    /// Add source of orderbooks into the aggregator
-    /// NOTE: This method should be taken out of that service and made
-    ///       independent so that subscriptions can be added on the fly,
-    ///       but for the conditions of the task it is enough.
-    pub async fn add_orderbook_source<G: crate::order_book::GetOrderBooksStream>(
+    /// Rates shared by mergers of all pairs, fixed ones are set here
+    pub fn fx_rates(&self) -> &FxRates {
+        &self.fx_rates
+    }
+
+    /// Start serving the pair, if it's not served yet
+    pub fn add_pair(&self, pair: Pair) {
+        let mut pairs = self.pairs.write().unwrap();
+        if pairs.contains_key(&pair) {
+            return;
+        }
+
+        let mut merger = OrderBookMerger::new(self.summary_size);
+        merger.set_staleness_threshold(self.staleness_threshold);
+        merger.set_quote_conversion(&pair.quote_currency, self.fx_rates.clone());
+        merger.set_taker_fees(self.taker_fees.clone());
+        merger.set_consolidated(self.consolidated);
+
+        let pair_aggregator = PairAggregator::new(merger);
+
+        if let Some(threshold) = self.staleness_threshold {
+            self.spawn_stale_books_eviction(&pair, &pair_aggregator, threshold);
+        }
+
+        pairs.insert(pair, pair_aggregator);
+    }
+
+    fn pair_aggregator(&self, pair: &Pair) -> Option<PairAggregator> {
+        self.pairs.read().unwrap().get(pair).cloned()
+    }
+
+    /// Rank and publish levels by prices with taker fees of exchanges, raw prices are kept
+    /// in levels. Exchanges without a fee are ranked by raw prices
+    pub async fn enable_fee_adjustment(
         &mut self,
+        taker_fees: HashMap<ExchangeName, rust_decimal::Decimal>,
+    ) {
+        self.taker_fees = Some(taker_fees);
+
+        let pair_aggregators = self
+            .pairs
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for pair_aggregator in pair_aggregators {
+            pair_aggregator
+                .merged_summary
+                .lock()
+                .await
+                .set_taker_fees(self.taker_fees.clone());
+        }
+    }
+
+    /// Publish one level per price with the breakdown by exchange instead of a level per exchange
+    pub async fn enable_level_consolidation(&mut self) {
+        self.consolidated = true;
+
+        let pair_aggregators = self
+            .pairs
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for pair_aggregator in pair_aggregators {
+            pair_aggregator
+                .merged_summary
+                .lock()
+                .await
+                .set_consolidated(true);
+        }
+    }
+
+    /// Drop books not updated for `threshold` from the summary
+    ///
+    /// Books are checked by timer, so subscribers are notified
+    /// even if no other exchange sends updates
+    pub async fn enable_stale_books_eviction(&mut self, threshold: Duration) {
+        self.staleness_threshold = Some(threshold);
+
+        let pairs = self
+            .pairs
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(pair, pair_aggregator)| (pair.clone(), pair_aggregator.clone()))
+            .collect::<Vec<_>>();
+
+        for (pair, pair_aggregator) in pairs {
+            pair_aggregator
+                .merged_summary
+                .lock()
+                .await
+                .set_staleness_threshold(Some(threshold));
+
+            self.spawn_stale_books_eviction(&pair, &pair_aggregator, threshold);
+        }
+    }
+
+    fn spawn_stale_books_eviction(
+        &self,
+        pair: &Pair,
+        pair_aggregator: &PairAggregator,
+        threshold: Duration,
+    ) {
+        let pair_aggregator = pair_aggregator.clone();
+
+        let abort_handle = self.spawn_task(
+            async move {
+                let mut interval = tokio::time::interval(threshold / 2);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+                loop {
+                    interval.tick().await;
+
+                    let mut merged_summary = pair_aggregator.merged_summary.lock().await;
+                    let evicted = merged_summary.evict_stale(Instant::now());
+                    if evicted.is_empty() {
+                        continue;
+                    }
+
+                    warn!("Evict stale orderbooks of {evicted:?}");
+
+                    pair_aggregator.publish(merged_summary.get_exchanges_summary());
+                }
+            }
+            .instrument(span!(
+                Level::INFO,
+                "stale books eviction",
+                pair = pair.to_string()
+            )),
+        );
+
+        let previous = self
+            .evictions
+            .lock()
+            .unwrap()
+            .insert(pair.clone(), abort_handle);
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+    }
+
+    fn summary_filter(
+        &self,
+        request: BookSummaryRequest,
+    ) -> Result<(PairAggregator, SummaryFilter), Status> {
+        let BookSummaryRequest {
+            base_currency,
+            quote_currency,
+            depth,
+            exchanges,
+            conflate: _,
+            decimal_encoding: _,
+        } = request;
+
+        let pair = if base_currency.is_empty() && quote_currency.is_empty() {
+            self.default_pair.clone()
+        } else {
+            Pair::new(&base_currency, &quote_currency)
+        };
+        let pair_aggregator = self.pair_aggregator(&pair).ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "Pair {pair} is not supported, served pairs: {pairs}",
+                pairs = self
+                    .pairs
+                    .read()
+                    .unwrap()
+                    .keys()
+                    .map(Pair::to_string)
+                    .join(", ")
+            ))
+        })?;
+
+        let depth = match depth as usize {
+            0 => None,
+            depth if depth > self.summary_size => {
+                return Err(Status::invalid_argument(format!(
+                    "Depth {depth} exceeds the summary size {}",
+                    self.summary_size
+                )))
+            }
+            depth => Some(depth),
+        };
+
+        Ok((
+            pair_aggregator,
+            SummaryFilter {
+                depth,
+                exchanges: exchanges
+                    .into_iter()
+                    .map(|exchange| exchange.to_lowercase())
+                    .collect(),
+                summary_size: self.summary_size,
+            },
+        ))
+    }
+
+    /// Add source of orderbooks of the default pair into the aggregator
+    pub async fn add_orderbook_source<G: crate::order_book::GetOrderBooksStream>(
+        &self,
+        exchange_name: ExchangeName,
+        summary_stream_getter: G,
+    ) -> Result<(), Error>
+    where
+        G::Error: std::error::Error + Send + Sync + 'static,
+        G::OrderBooksStream: Unpin + Send + Sync + 'static,
+    {
+        self.add_pair_orderbook_source(exchange_name, &self.default_pair, summary_stream_getter)
+            .await
+    }
+
+    /// Add source of orderbooks of the pair into the aggregator, the pair is served from now on
+    pub async fn add_pair_orderbook_source<G: crate::order_book::GetOrderBooksStream>(
+        &self,
         exchange_name: ExchangeName,
+        pair: &Pair,
         summary_stream_getter: G,
     ) -> Result<(), Error>
     where
         G::Error: std::error::Error + Send + Sync + 'static,
         G::OrderBooksStream: Unpin + Send + Sync + 'static,
     {
+        self.add_quoted_pair_orderbook_source(
+            exchange_name,
+            pair,
+            &pair.quote_currency,
+            summary_stream_getter,
+        )
+        .await
+    }
+
+    /// Add source of orderbooks of the pair quoted in `quote_currency` on the exchange,
+    /// prices are converted into the pair quote by fx rates
+    pub async fn add_quoted_pair_orderbook_source<G: crate::order_book::GetOrderBooksStream>(
+        &self,
+        exchange_name: ExchangeName,
+        pair: &Pair,
+        quote_currency: &str,
+        summary_stream_getter: G,
+    ) -> Result<(), Error>
+    where
+        G::Error: std::error::Error + Send + Sync + 'static,
+        G::OrderBooksStream: Unpin + Send + Sync + 'static,
+    {
+        let source = (exchange_name, pair.clone());
+        if self.sources.lock().unwrap().contains_key(&source) {
+            return Err(Error::SourceExists {
+                exchange_name: source.0,
+                pair: source.1,
+            });
+        }
+
+        let reconnect_stats = summary_stream_getter.reconnect_stats();
         let mut stream = summary_stream_getter
-            .get_order_books_stream(self.base_currency.as_str(), self.quote_currency.as_str())
+            .get_order_books_stream(&pair.base_currency, quote_currency)
             .await
             .map_err(|err| Error::SummaryStreamError(Arc::new(err)))?;
 
-        let merged_summary = self.merged_summary.clone();
-        let summary_sender = self.orderbook_sender.clone();
+        self.add_pair(pair.clone());
+        let pair_aggregator = self.pair_aggregator(pair).expect("Pair added above");
+        let exchange_name = source.0.clone();
+        pair_aggregator
+            .merged_summary
+            .lock()
+            .await
+            .set_exchange_quote(&exchange_name, quote_currency);
         let trace_span = span!(
             Level::TRACE,
             "stream handler",
-            exchange_name = exchange_name
+            exchange_name = exchange_name,
+            pair = pair.to_string()
         );
-        let info_span = span!(Level::INFO, "stream handler", exchange_name = exchange_name);
+        let info_span = span!(
+            Level::INFO,
+            "stream handler",
+            exchange_name = exchange_name,
+            pair = pair.to_string()
+        );
+
+        let id = self.next_source_id.fetch_add(1, Ordering::Relaxed);
+        let sources = self.sources.clone();
+        let task_source = source.clone();
 
-        self.orderbook_source_tasks.spawn(
+        // Sources are locked until the task is registered, so that it can't end before that
+        let mut sources_guard = self.sources.lock().unwrap();
+        let abort_handle = self.spawn_task(
             async move {
                 info!("Start stream handler task");
 
@@ -92,30 +602,144 @@ impl OrderbookAggregatorService {
 
                     match order_book {
                         Ok(order_book) => {
-                            let summary = merged_summary
-                                .lock()
-                                .await
-                                .insert_and_get(&exchange_name, order_book);
-
-                            match summary_sender.send(Ok(summary)) {
-                                Ok(receiver_count) => {
-                                    info!("Send summary to {receiver_count} receiver")
-                                }
-                                Err(_) => info!("No subscribers"),
-                            }
+                            let mut merged_summary = pair_aggregator.merged_summary.lock().await;
+                            let summary = merged_summary.insert_and_get(&exchange_name, order_book);
+                            pair_aggregator.publish(summary);
                         }
                         Err(err) => {
                             error!("Error while receive order book: {err:?}")
                         }
                     }
                 }
+
+                warn!("Orderbooks stream ended, remove the source");
+
+                // The entry may belong to a source added again after this one was removed
+                let mut merged_summary = pair_aggregator.merged_summary.lock().await;
+                let mut sources = sources.lock().unwrap();
+                if sources.get(&task_source).is_some_and(|task| task.id == id) {
+                    sources.remove(&task_source);
+                    if merged_summary.remove(&exchange_name) {
+                        pair_aggregator.publish(merged_summary.get_exchanges_summary());
+                    }
+                }
             }
             .instrument(trace_span)
             .instrument(info_span),
         );
 
+        // The same source may have been added concurrently while connecting
+        let task = SourceTask {
+            id,
+            abort_handle,
+            reconnect_stats,
+        };
+        if let Some(previous) = sources_guard.insert(source, task) {
+            previous.abort_handle.abort();
+        }
+
+        Ok(())
+    }
+
+    /// Keep the rate of the pair by mid prices of the exchange books, for conversion of books
+    /// quoted in its currencies
+    ///
+    /// The rate is not served as a pair and replaces the fixed one from the first book
+    pub async fn add_fx_rate_source<G: crate::order_book::GetOrderBooksStream>(
+        &self,
+        exchange_name: ExchangeName,
+        pair: &Pair,
+        summary_stream_getter: G,
+    ) -> Result<(), Error>
+    where
+        G::Error: std::error::Error + Send + Sync + 'static,
+        G::OrderBooksStream: Unpin + Send + Sync + 'static,
+    {
+        let mut stream = summary_stream_getter
+            .get_order_books_stream(&pair.base_currency, &pair.quote_currency)
+            .await
+            .map_err(|err| Error::SummaryStreamError(Arc::new(err)))?;
+
+        let fx_rates = self.fx_rates.clone();
+        let pair = pair.clone();
+        let span = span!(
+            Level::INFO,
+            "fx rate handler",
+            exchange_name = exchange_name,
+            pair = pair.to_string()
+        );
+
+        self.spawn_task(
+            async move {
+                info!("Start fx rate handler task");
+
+                while let Some(order_book) = stream.next().await {
+                    match order_book {
+                        Ok(order_book) => match fx_rates.set_mid_price(&pair, &order_book) {
+                            Some(rate) => trace!("Rate of {pair} is {rate}"),
+                            None => warn!("Skip one-sided orderbook of {pair}"),
+                        },
+                        Err(err) => {
+                            error!("Error while receive order book: {err:?}")
+                        }
+                    }
+                }
+            }
+            .instrument(span),
+        );
+
         Ok(())
     }
+
+    /// Stop the source of orderbooks of the pair and drop its book from the summary
+    pub async fn remove_orderbook_source(
+        &self,
+        exchange_name: &str,
+        pair: &Pair,
+    ) -> Result<(), Error> {
+        let source = (exchange_name.to_owned(), pair.clone());
+        let task = self
+            .sources
+            .lock()
+            .unwrap()
+            .remove(&source)
+            .ok_or_else(|| Error::SourceNotFound {
+                exchange_name: source.0.clone(),
+                pair: source.1.clone(),
+            })?;
+        task.abort_handle.abort();
+
+        // Once the task is aborted, it can't insert the book after the removal under the same lock
+        if let Some(pair_aggregator) = self.pair_aggregator(pair) {
+            let mut merged_summary = pair_aggregator.merged_summary.lock().await;
+            if merged_summary.remove(exchange_name) {
+                pair_aggregator.publish(merged_summary.get_exchanges_summary());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Exchanges and pairs of all added sources
+    pub fn sources(&self) -> Vec<(ExchangeName, Pair)> {
+        self.sources
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .sorted()
+            .collect()
+    }
+
+    /// Counters of reconnects of the source, `None` if it is not added or doesn't reconnect
+    pub fn reconnect_stats(&self, exchange_name: &str, pair: &Pair) -> Option<Arc<ReconnectStats>> {
+        self.sources
+            .lock()
+            .unwrap()
+            .get(&(exchange_name.to_owned(), pair.clone()))?
+            .reconnect_stats
+            .clone()
+    }
 }
 
 #[tonic::async_trait]
@@ -124,27 +748,39 @@ impl OrderbookAggregator for OrderbookAggregatorService {
 
     async fn book_summary(
         &self,
-        _: Request<Empty>,
+        request: Request<BookSummaryRequest>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
-        Ok(Response::new(
-            BroadcastStream::new(self.orderbook_sender.subscribe()).filter_map(
-                |result_with_summary| match result_with_summary {
-                    Ok(result_with_summary) => {
-                        trace!("Send {result_with_summary:?} via stream");
-                        Some(result_with_summary.map_err(tonic::Status::from))
-                    }
-                    Err(BroadcastStreamRecvError::Lagged(lagged)) => {
-                        warn!("Lagged {lagged} messages");
-                        None
-                    }
-                },
-            ),
-        ))
+        let request = request.into_inner();
+        let conflate = request.conflate;
+        let decimal_encoding = request.decimal_encoding();
+        let (pair_aggregator, summary_filter) = self.summary_filter(request)?;
+
+        Ok(Response::new(pair_aggregator.subscribe(conflate).map(
+            move |result_with_summary| {
+                trace!("Send {result_with_summary:?} via stream");
+                result_with_summary
+                    .map(
+                        |SubscriberUpdate {
+                             summary,
+                             exchanges_summary,
+                             lagged,
+                         }| Summary {
+                            lagged,
+                            ..summary_filter.apply(summary, &exchanges_summary)
+                        },
+                    )
+                    .map(|summary| match decimal_encoding {
+                        DecimalEncoding::SignedScaled => summary,
+                        legacy => summary.to_encoding(legacy),
+                    })
+                    .map_err(tonic::Status::from)
+            },
+        )))
     }
 }
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{assert_matches::assert_matches, str::FromStr};
 
     use rust_decimal::Decimal;
     use tokio_stream::wrappers::BroadcastStream;
@@ -164,11 +800,23 @@ mod tests {
     #[derive(Clone)]
     struct MockOrderBookStream {
         order_books: Vec<OrderBook>,
+        /// Whether the stream ends after the order books, otherwise it stays open like exchange ones
+        ends: bool,
     }
 
     impl MockOrderBookStream {
         fn new(order_books: Vec<OrderBook>) -> Self {
-            Self { order_books }
+            Self {
+                order_books,
+                ends: false,
+            }
+        }
+
+        fn ending(order_books: Vec<OrderBook>) -> Self {
+            Self {
+                order_books,
+                ends: true,
+            }
         }
     }
 
@@ -185,9 +833,12 @@ mod tests {
             _base_currency: &str,
             _quote_currency: &str,
         ) -> Result<Self::OrderBooksStream, Self::Error> {
-            Ok(Box::pin(tokio_stream::iter(
-                self.order_books.clone().into_iter().map(Ok),
-            )))
+            let order_books = tokio_stream::iter(self.order_books.clone().into_iter().map(Ok));
+            Ok(Box::pin(if self.ends {
+                Either::Left(order_books)
+            } else {
+                Either::Right(order_books.chain(futures_util::stream::pending()))
+            }))
         }
     }
 
@@ -242,7 +893,7 @@ mod tests {
         let mock_stream1 = MockOrderBookStream::new(vec![order_book1]);
         let mock_stream2 = MockOrderBookStream::new(vec![order_book2]);
 
-        let mut aggregator =
+        let aggregator =
             OrderbookAggregatorService::new(base_currency, quote_currency, summary_size);
         aggregator
             .add_orderbook_source(exchange1.clone(), mock_stream1)
@@ -254,21 +905,29 @@ mod tests {
             .await
             .unwrap();
 
-        let mut receiver = BroadcastStream::new(aggregator.orderbook_sender.subscribe());
+        let mut receiver = BroadcastStream::new(
+            aggregator
+                .pair_aggregator(&aggregator.default_pair)
+                .unwrap()
+                .orderbook_sender
+                .subscribe(),
+        );
 
         // Skip first summary, equal for first exchange
         _ = receiver.next().await;
         if let Some(result_with_summary) = receiver.next().await {
-            let (asks, bids, spread) = match result_with_summary.unwrap() {
-                Ok(crate::proto::Summary {
-                    asks,
-                    bids,
-                    spread: Some(spread),
-                }) => (asks, bids, spread),
-                other => panic!("Unexpected summary: {other:?}"),
-            };
+            let (asks, bids, spread) =
+                match result_with_summary.unwrap().map(|update| update.summary) {
+                    Ok(crate::proto::Summary {
+                        asks,
+                        bids,
+                        spread: Some(spread),
+                        ..
+                    }) => (asks, bids, spread),
+                    other => panic!("Unexpected summary: {other:?}"),
+                };
 
-            assert_eq!(spread, decimal!("10").into());
+            assert_eq!(decimal!(spread), decimal!("10"));
             assert_eq!(asks.len(), 2);
             assert_eq!(bids.len(), 2);
 
@@ -307,4 +966,436 @@ mod tests {
             panic!("Failed to receive first summary from the aggregator");
         }
     }
+
+    #[tokio::test]
+    async fn test_stale_books_eviction() {
+        let exchange = "exchange".to_string();
+
+        let mut aggregator = OrderbookAggregatorService::new("BTC", "USD", 2);
+        aggregator
+            .enable_stale_books_eviction(Duration::from_millis(50))
+            .await;
+
+        let mut receiver = BroadcastStream::new(
+            aggregator
+                .pair_aggregator(&aggregator.default_pair)
+                .unwrap()
+                .orderbook_sender
+                .subscribe(),
+        );
+
+        aggregator
+            .add_orderbook_source(
+                exchange.clone(),
+                MockOrderBookStream::new(vec![create_order_book(
+                    vec![(decimal!("100.0"), decimal!("1.0"))],
+                    vec![(decimal!("110.0"), decimal!("3.0"))],
+                )]),
+            )
+            .await
+            .unwrap();
+
+        let summary = receiver.next().await.unwrap().unwrap().unwrap().summary;
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.asks.len(), 1);
+
+        // The source is silent from now on, so the next summary is caused by eviction
+        let summary = receiver.next().await.unwrap().unwrap().unwrap().summary;
+        assert_eq!(summary.bids, vec![]);
+        assert_eq!(summary.asks, vec![]);
+        assert_eq!(summary.spread, None);
+    }
+
+    #[tokio::test]
+    async fn test_stale_books_eviction_enabled_twice() {
+        let mut aggregator = OrderbookAggregatorService::new("BTC", "USD", 2);
+        aggregator
+            .enable_stale_books_eviction(Duration::from_millis(50))
+            .await;
+        aggregator
+            .enable_stale_books_eviction(Duration::from_millis(100))
+            .await;
+
+        tokio::task::yield_now().await;
+
+        // The task of the first call is replaced
+        let mut tasks = aggregator.orderbook_source_tasks.lock().unwrap();
+        while let Some(finished) = tasks.try_join_next() {
+            assert!(finished.unwrap_err().is_cancelled());
+        }
+        assert_eq!(tasks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_book_summary_request() {
+        let exchange1 = "exchange1".to_string();
+        let exchange2 = "exchange2".to_string();
+
+        let aggregator = OrderbookAggregatorService::new("BTC", "USD", 2);
+
+        let status = aggregator
+            .book_summary(Request::new(BookSummaryRequest {
+                base_currency: "eth".to_owned(),
+                quote_currency: "usd".to_owned(),
+                ..Default::default()
+            }))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let status = aggregator
+            .book_summary(Request::new(BookSummaryRequest {
+                depth: 3,
+                ..Default::default()
+            }))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let mut stream = aggregator
+            .book_summary(Request::new(BookSummaryRequest {
+                base_currency: "btc".to_owned(),
+                quote_currency: "usd".to_owned(),
+                depth: 1,
+                exchanges: vec!["Exchange2".to_owned()],
+                decimal_encoding: DecimalEncoding::SignedScaled as i32,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        aggregator
+            .add_orderbook_source(
+                exchange1,
+                MockOrderBookStream::new(vec![create_order_book(
+                    vec![(decimal!("100.0"), decimal!("1.0"))],
+                    vec![(decimal!("110.0"), decimal!("3.0"))],
+                )]),
+            )
+            .await
+            .unwrap();
+        aggregator
+            .add_orderbook_source(
+                exchange2.clone(),
+                MockOrderBookStream::new(vec![create_order_book(
+                    vec![
+                        (decimal!("95.0"), decimal!("1.5")),
+                        (decimal!("85.0"), decimal!("2.5")),
+                    ],
+                    vec![(decimal!("115.0"), decimal!("3.5"))],
+                )]),
+            )
+            .await
+            .unwrap();
+
+        tokio::pin!(stream);
+
+        // Only the first exchange is merged yet
+        let summary = stream.next().await.unwrap().unwrap();
+        assert_eq!(summary.bids, vec![]);
+        assert_eq!(summary.spread, None);
+
+        let summary = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            summary.bids,
+            vec![PriceLevel {
+                price: decimal!("95.0"),
+                quantity: decimal!("1.5"),
+            }
+            .to_proto(&exchange2)]
+        );
+        assert_eq!(
+            summary.asks,
+            vec![PriceLevel {
+                price: decimal!("115.0"),
+                quantity: decimal!("3.5"),
+            }
+            .to_proto(&exchange2)]
+        );
+        let spread = summary.spread.unwrap();
+        assert_eq!(decimal!(spread), decimal!("20"));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_decimal_encoding_by_default() {
+        let exchange = "exchange".to_string();
+        let aggregator = OrderbookAggregatorService::new("BTC", "USD", 2);
+
+        let mut stream = aggregator
+            .book_summary(Request::new(BookSummaryRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+
+        aggregator
+            .add_orderbook_source(
+                exchange,
+                MockOrderBookStream::new(vec![create_order_book(
+                    vec![(decimal!("100.0"), decimal!("1.0"))],
+                    vec![(decimal!("110.0"), decimal!("3.0"))],
+                )]),
+            )
+            .await
+            .unwrap();
+
+        tokio::pin!(stream);
+
+        let summary = stream.next().await.unwrap().unwrap();
+        let price = summary.bids[0].price.as_ref().unwrap();
+        assert_eq!(price.encoding(), DecimalEncoding::LegacyScale25);
+        assert_eq!(rust_decimal::Decimal::from(price), decimal!("100"));
+    }
+
+    #[tokio::test]
+    async fn test_multiple_pairs() {
+        let exchange = "exchange".to_string();
+        let eth_btc = Pair::new("ETH", "BTC");
+
+        let aggregator = OrderbookAggregatorService::new("BTC", "USD", 2);
+        aggregator.add_pair(eth_btc.clone());
+
+        let mut stream = aggregator
+            .book_summary(Request::new(BookSummaryRequest {
+                base_currency: "eth".to_owned(),
+                quote_currency: "btc".to_owned(),
+                decimal_encoding: DecimalEncoding::SignedScaled as i32,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        aggregator
+            .add_orderbook_source(
+                exchange.clone(),
+                MockOrderBookStream::new(vec![create_order_book(
+                    vec![(decimal!("100.0"), decimal!("1.0"))],
+                    vec![(decimal!("110.0"), decimal!("3.0"))],
+                )]),
+            )
+            .await
+            .unwrap();
+        aggregator
+            .add_pair_orderbook_source(
+                exchange.clone(),
+                &eth_btc,
+                MockOrderBookStream::new(vec![create_order_book(
+                    vec![(decimal!("0.065"), decimal!("10.0"))],
+                    vec![(decimal!("0.066"), decimal!("12.0"))],
+                )]),
+            )
+            .await
+            .unwrap();
+
+        tokio::pin!(stream);
+
+        let summary = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            summary.bids,
+            vec![PriceLevel {
+                price: decimal!("0.065"),
+                quantity: decimal!("10.0"),
+            }
+            .to_proto(&exchange)]
+        );
+        assert_eq!(
+            summary.asks,
+            vec![PriceLevel {
+                price: decimal!("0.066"),
+                quantity: decimal!("12.0"),
+            }
+            .to_proto(&exchange)]
+        );
+    }
+
+    async fn next_sequence(
+        stream: &mut (impl Stream<
+            Item = Result<Result<SummaryUpdate, super::Error>, BroadcastStreamRecvError>,
+        > + Unpin),
+    ) -> Option<u64> {
+        match tokio::time::timeout(Duration::from_millis(100), stream.next()).await {
+            Ok(Some(Ok(Ok(update)))) => Some(update.sequence),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latest_summary_for_new_subscriber() {
+        let pair_aggregator = PairAggregator::new(OrderBookMerger::default());
+
+        let mut early_subscriber = Box::pin(pair_aggregator.updates());
+
+        (0..3).for_each(|_| pair_aggregator.publish(Summary::default()));
+
+        let mut late_subscriber = Box::pin(pair_aggregator.updates());
+        assert_eq!(next_sequence(&mut late_subscriber).await, Some(2));
+
+        pair_aggregator.publish(Summary::default());
+        assert_eq!(next_sequence(&mut late_subscriber).await, Some(3));
+        assert_eq!(next_sequence(&mut late_subscriber).await, None);
+
+        for sequence in 0..4 {
+            assert_eq!(next_sequence(&mut early_subscriber).await, Some(sequence));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_no_missed_or_duplicated_summary_at_handover() {
+        const UPDATES: usize = 20_000;
+
+        let pair_aggregator = PairAggregator::new(OrderBookMerger::default());
+        pair_aggregator.publish(Summary::default());
+
+        let publisher = tokio::task::spawn_blocking({
+            let pair_aggregator = pair_aggregator.clone();
+            move || {
+                for _ in 0..UPDATES {
+                    let _merged_summary = pair_aggregator.merged_summary.blocking_lock();
+                    pair_aggregator.publish(Summary::default());
+                }
+            }
+        });
+
+        let mut handovers = 0;
+        while !publisher.is_finished() {
+            let mut subscriber = Box::pin(pair_aggregator.updates());
+            let mut expected = next_sequence(&mut subscriber).await.unwrap();
+
+            // Subscriber may lag behind the publisher, then the stream has an error instead of update
+            for _ in 0..3 {
+                match next_sequence(&mut subscriber).await {
+                    Some(sequence) => {
+                        expected += 1;
+                        assert_eq!(sequence, expected);
+                    }
+                    None => break,
+                }
+            }
+
+            handovers += 1;
+        }
+
+        publisher.await.unwrap();
+        assert!(handovers > 0);
+    }
+
+    async fn next_lagged(
+        stream: &mut (impl Stream<Item = Result<SubscriberUpdate, super::Error>> + Unpin),
+    ) -> Option<u64> {
+        match tokio::time::timeout(Duration::from_millis(100), stream.next()).await {
+            Ok(Some(Ok(update))) => Some(update.lagged),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lagged_subscriber_fast_forward() {
+        let pair_aggregator = PairAggregator::new(OrderBookMerger::default());
+        pair_aggregator.publish(Summary::default());
+
+        let mut subscriber = Box::pin(pair_aggregator.subscribe(false));
+        assert_eq!(next_lagged(&mut subscriber).await, Some(0));
+
+        // Overflow the channel, the subscriber jumps straight to the 30th summary
+        (0..30).for_each(|_| pair_aggregator.publish(Summary::default()));
+        assert_eq!(next_lagged(&mut subscriber).await, Some(29));
+        assert_eq!(next_lagged(&mut subscriber).await, None);
+
+        pair_aggregator.publish(Summary::default());
+        assert_eq!(next_lagged(&mut subscriber).await, Some(29));
+    }
+
+    #[tokio::test]
+    async fn test_conflated_subscriber() {
+        let pair_aggregator = PairAggregator::new(OrderBookMerger::default());
+
+        let mut subscriber = Box::pin(pair_aggregator.subscribe(true));
+        assert_eq!(next_lagged(&mut subscriber).await, None);
+
+        pair_aggregator.publish(Summary::default());
+        assert_eq!(next_lagged(&mut subscriber).await, Some(0));
+
+        (0..3).for_each(|_| pair_aggregator.publish(Summary::default()));
+        assert_eq!(next_lagged(&mut subscriber).await, Some(2));
+        assert_eq!(next_lagged(&mut subscriber).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_source() {
+        let exchange = "exchange".to_string();
+        let pair = Pair::new("BTC", "USD");
+        let order_books = MockOrderBookStream::new(vec![create_order_book(
+            vec![(decimal!("100.0"), decimal!("1.0"))],
+            vec![(decimal!("110.0"), decimal!("3.0"))],
+        )]);
+
+        let aggregator = OrderbookAggregatorService::new("BTC", "USD", 2);
+        let mut subscriber = Box::pin(aggregator.pair_aggregator(&pair).unwrap().updates());
+
+        aggregator
+            .add_orderbook_source(exchange.clone(), order_books.clone())
+            .await
+            .unwrap();
+        assert_matches!(
+            aggregator
+                .add_orderbook_source(exchange.clone(), order_books)
+                .await,
+            Err(super::Error::SourceExists { .. })
+        );
+        assert_eq!(aggregator.sources(), vec![(exchange.clone(), pair.clone())]);
+
+        let summary = subscriber.next().await.unwrap().unwrap().unwrap().summary;
+        assert_eq!(summary.bids.len(), 1);
+
+        aggregator
+            .remove_orderbook_source(&exchange, &pair)
+            .await
+            .unwrap();
+        assert_eq!(aggregator.sources(), vec![]);
+
+        let summary = subscriber.next().await.unwrap().unwrap().unwrap().summary;
+        assert_eq!(summary.bids, vec![]);
+        assert_eq!(summary.asks, vec![]);
+
+        assert_matches!(
+            aggregator.remove_orderbook_source(&exchange, &pair).await,
+            Err(super::Error::SourceNotFound { .. })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ended_source_removed() {
+        let exchange = "exchange".to_string();
+        let pair = Pair::new("BTC", "USD");
+        let order_books = MockOrderBookStream::ending(vec![create_order_book(
+            vec![(decimal!("100.0"), decimal!("1.0"))],
+            vec![(decimal!("110.0"), decimal!("3.0"))],
+        )]);
+
+        let aggregator = OrderbookAggregatorService::new("BTC", "USD", 2);
+        let mut subscriber = Box::pin(aggregator.pair_aggregator(&pair).unwrap().updates());
+
+        aggregator
+            .add_orderbook_source(exchange.clone(), order_books.clone())
+            .await
+            .unwrap();
+
+        let summary = subscriber.next().await.unwrap().unwrap().unwrap().summary;
+        assert_eq!(summary.bids.len(), 1);
+
+        // Book of the ended source is removed with its entry
+        let summary = subscriber.next().await.unwrap().unwrap().unwrap().summary;
+        assert_eq!(summary.bids, vec![]);
+        assert_eq!(aggregator.sources(), vec![]);
+
+        aggregator
+            .add_orderbook_source(exchange.clone(), order_books)
+            .await
+            .unwrap();
+        let summary = subscriber.next().await.unwrap().unwrap().unwrap().summary;
+        assert_eq!(summary.bids.len(), 1);
+    }
 }
@@ -1,16 +1,43 @@
-use std::{cmp, collections::HashMap};
+use std::{
+    cmp,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
+use itertools::{Either, Itertools};
 use merging_iterator::MergeSortedIter;
+use rust_decimal::Decimal;
 use tracing::*;
 
-use crate::{order_book::OrderBook, proto::Summary};
+use super::FxRates;
+use crate::{
+    order_book::{OrderBook, PriceLevel, Side},
+    proto::{self, Summary},
+};
 
 pub type ExchangeName = String;
 
+#[derive(Debug)]
+struct ReceivedOrderBook {
+    order_book: OrderBook,
+    received_at: Instant,
+}
+
 #[derive(Debug)]
 pub struct OrderBookMerger {
-    exchanges_summaries: HashMap<ExchangeName, OrderBook>,
+    exchanges_summaries: HashMap<ExchangeName, ReceivedOrderBook>,
     summary_size: usize,
+    /// Books received earlier than this are evicted from the summary
+    staleness_threshold: Option<Duration>,
+    /// Quote currency of the summary, prices of books in other quotes are converted into it
+    quote_currency: String,
+    /// Quote currencies of exchanges whose books are not in the summary quote
+    exchange_quotes: HashMap<ExchangeName, String>,
+    fx_rates: FxRates,
+    /// Taker fees by exchange, levels are ranked by fee-adjusted prices if set
+    taker_fees: Option<HashMap<ExchangeName, Decimal>>,
+    /// Levels of exchanges at the same price are combined into one
+    consolidated: bool,
 }
 impl OrderBookMerger {
     pub fn new(summary_size: usize) -> Self {
@@ -19,52 +46,206 @@ impl OrderBookMerger {
             ..Self::default()
         }
     }
+
+    pub fn summary_size(&self) -> usize {
+        self.summary_size
+    }
+
+    pub fn set_staleness_threshold(&mut self, staleness_threshold: Option<Duration>) {
+        self.staleness_threshold = staleness_threshold;
+    }
+
+    /// Convert prices of books quoted in other currencies into `quote_currency` by `fx_rates`
+    pub fn set_quote_conversion(&mut self, quote_currency: &str, fx_rates: FxRates) {
+        self.quote_currency = quote_currency.to_lowercase();
+        self.fx_rates = fx_rates;
+    }
+
+    /// Rank and publish prices with taker fees of exchanges (zero if missing), raw ones if `None`
+    pub fn set_taker_fees(&mut self, taker_fees: Option<HashMap<ExchangeName, Decimal>>) {
+        self.taker_fees = taker_fees;
+    }
+
+    /// Combine levels at the same price into one with the breakdown by exchange,
+    /// so that they take one place of the summary
+    pub fn set_consolidated(&mut self, consolidated: bool) {
+        self.consolidated = consolidated;
+    }
+
+    /// Books of the exchange are quoted in `quote_currency`, converted if it's not the summary one
+    pub fn set_exchange_quote(&mut self, exchange: &ExchangeName, quote_currency: &str) {
+        let quote_currency = quote_currency.to_lowercase();
+        if quote_currency == self.quote_currency {
+            self.exchange_quotes.remove(exchange);
+        } else {
+            self.exchange_quotes
+                .insert(exchange.clone(), quote_currency);
+        }
+    }
 }
 impl Default for OrderBookMerger {
     fn default() -> Self {
         Self {
             exchanges_summaries: Default::default(),
             summary_size: 10,
+            staleness_threshold: None,
+            quote_currency: Default::default(),
+            exchange_quotes: Default::default(),
+            fx_rates: Default::default(),
+            taker_fees: None,
+            consolidated: false,
         }
     }
 }
 impl OrderBookMerger {
+    /// Insert the book, evict stale ones and get [`Self::get_exchanges_summary`]
     pub fn insert_and_get(&mut self, exchange: &ExchangeName, order_book: OrderBook) -> Summary {
-        self.insert(exchange, order_book);
-        self.get_summary()
+        let now = Instant::now();
+        self.insert_at(exchange, order_book, now);
+        self.evict_stale(now);
+        self.get_exchanges_summary()
     }
 
     fn insert(&mut self, exchange: &ExchangeName, order_book: OrderBook) {
+        self.insert_at(exchange, order_book, Instant::now())
+    }
+
+    fn insert_at(&mut self, exchange: &ExchangeName, order_book: OrderBook, received_at: Instant) {
         // TODO Optimise insertion so there is no string copying every time
-        self.exchanges_summaries
-            .insert(exchange.clone(), order_book);
+        self.exchanges_summaries.insert(
+            exchange.clone(),
+            ReceivedOrderBook {
+                order_book,
+                received_at,
+            },
+        );
+    }
+
+    /// Remove the book of the exchange, returns whether it was merged
+    pub fn remove(&mut self, exchange: &str) -> bool {
+        self.exchange_quotes.remove(exchange);
+        self.exchanges_summaries.remove(exchange).is_some()
     }
 
-    fn get_summary(&self) -> Summary {
+    /// Remove books older than the staleness threshold, returns the exchanges whose books were removed
+    pub fn evict_stale(&mut self, now: Instant) -> Vec<ExchangeName> {
+        let Some(threshold) = self.staleness_threshold else {
+            return vec![];
+        };
+
+        let stale = self
+            .exchanges_summaries
+            .iter()
+            .filter(|(_, book)| now.saturating_duration_since(book.received_at) > threshold)
+            .map(|(exchange, _)| exchange.clone())
+            .collect::<Vec<_>>();
+
+        stale.iter().for_each(|exchange| {
+            self.exchanges_summaries.remove(exchange);
+        });
+
+        stale
+    }
+
+    /// Levels sorted by price, consolidated if configured so
+    fn consolidate<'l>(
+        &self,
+        levels: impl Iterator<Item = proto::PriceLevel> + 'l,
+    ) -> impl Iterator<Item = proto::PriceLevel> + 'l {
+        if self.consolidated {
+            Either::Left(levels.peekable().batching(|levels| {
+                let mut consolidated = levels.next()?.consolidated();
+                while let Some(level) = levels.next_if(|level| consolidated.same_price(level)) {
+                    consolidated.consolidate(level);
+                }
+                Some(consolidated)
+            }))
+        } else {
+            Either::Right(levels)
+        }
+    }
+
+    /// Top levels of all exchanges
+    pub fn get_summary(&self) -> Summary {
+        self.get_exchanges_summary()
+            .filtered(self.summary_size, &[])
+    }
+
+    /// Merged levels within the summary size of each exchange, so that the summary of any
+    /// exchanges is their levels of it cut to the summary size
+    pub fn get_exchanges_summary(&self) -> Summary {
         info!(
             "Exchanges for merge: {exchanges:?}",
             exchanges = self.exchanges_summaries.keys(),
         );
 
-        let asks = MergeSortedIter::new(
-            self.exchanges_summaries
-                .iter()
-                .map(|(exchange, sum)| sum.asks.iter().map(|l| l.to_proto(exchange))),
+        let fee = |exchange: &str| {
+            self.taker_fees
+                .as_ref()
+                .map(|fees| fees.get(exchange).copied().unwrap_or_default())
+        };
+        let to_proto = |exchange: &str,
+                        conversion: Option<(&str, Decimal)>,
+                        side: Side,
+                        level: &PriceLevel| {
+            let level = match conversion {
+                Some((quote_currency, rate)) => {
+                    level.to_converted_proto(exchange, quote_currency, rate)?
+                }
+                None => level.to_proto(exchange),
+            };
+            Some(match fee(exchange) {
+                Some(fee) => level.with_taker_fee(side, fee),
+                None => level,
+            })
+        };
+
+        // Books without a rate to the summary quote can't be merged until it's known
+        let (asks, bids): (Vec<Vec<_>>, Vec<Vec<_>>) = self
+            .exchanges_summaries
+            .iter()
+            .filter_map(|(exchange, book)| {
+                let conversion = match self.exchange_quotes.get(exchange) {
+                    Some(quote_currency) => {
+                        match self.fx_rates.rate(quote_currency, &self.quote_currency) {
+                            Some(rate) => Some((quote_currency.as_str(), rate)),
+                            None => {
+                                warn!(
+                                    "Skip book of {exchange}, no rate of {quote_currency} to {}",
+                                    self.quote_currency
+                                );
+                                return None;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                let levels = |side: Side, levels: &[PriceLevel]| {
+                    levels
+                        .iter()
+                        .take(self.summary_size)
+                        .map(|level| to_proto(exchange, conversion, side, level))
+                        .collect::<Option<Vec<_>>>()
+                };
+                let asks = levels(Side::Ask, &book.order_book.asks);
+                let bids = levels(Side::Bid, &book.order_book.bids);
+                if asks.is_none() || bids.is_none() {
+                    warn!("Skip book of {exchange}, its prices overflow by the conversion");
+                }
+                asks.zip(bids)
+            })
+            .unzip();
+
+        let asks = MergeSortedIter::new(asks.into_iter().map(Vec::into_iter));
+        let bids = MergeSortedIter::new(
+            bids.into_iter()
+                .map(|bids| bids.into_iter().map(cmp::Reverse)),
         )
-        .take(self.summary_size)
-        .collect();
-
-        let bids = MergeSortedIter::new(self.exchanges_summaries.iter().map(
-            |(exchange, order_book)| {
-                order_book
-                    .bids
-                    .iter()
-                    .map(|l| cmp::Reverse(l.to_proto(exchange)))
-            },
-        ))
-        .take(self.summary_size)
-        .map(|reversed| reversed.0)
-        .collect();
+        .map(|reversed| reversed.0);
+
+        let asks = self.consolidate(asks).collect();
+        let bids = self.consolidate(bids).collect();
 
         Summary::new(asks, bids)
     }
@@ -74,10 +255,8 @@ impl OrderBookMerger {
 mod tests {
     use std::str::FromStr;
 
-    use rust_decimal::Decimal;
-
     use super::*;
-    use crate::order_book::PriceLevel;
+    use crate::order_book::Pair;
 
     macro_rules! decimal {
         ($s:literal) => {
@@ -196,4 +375,269 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_evict_stale() {
+        let exchange1 = "exchange1".to_string();
+        let exchange2 = "exchange2".to_string();
+
+        let mut merged_summary = OrderBookMerger::default();
+        merged_summary.set_staleness_threshold(Some(Duration::from_secs(1)));
+
+        let now = Instant::now();
+        merged_summary.insert_at(
+            &exchange1,
+            create_order_book(
+                vec![(decimal!("100.0"), decimal!("1.0"))],
+                vec![(decimal!("110.0"), decimal!("3.0"))],
+            ),
+            now,
+        );
+        merged_summary.insert_at(
+            &exchange2,
+            create_order_book(
+                vec![(decimal!("95.0"), decimal!("1.5"))],
+                vec![(decimal!("115.0"), decimal!("3.5"))],
+            ),
+            now + Duration::from_secs(2),
+        );
+
+        assert_eq!(
+            merged_summary.evict_stale(now + Duration::from_secs(1)),
+            Vec::<ExchangeName>::new()
+        );
+        assert_eq!(
+            merged_summary.evict_stale(now + Duration::from_millis(2500)),
+            vec![exchange1]
+        );
+
+        let summary = merged_summary.get_summary();
+        assert_eq!(
+            summary.bids,
+            vec![PriceLevel {
+                price: decimal!("95.0"),
+                quantity: decimal!("1.5"),
+            }
+            .to_proto(&exchange2)]
+        );
+        assert_eq!(
+            summary.asks,
+            vec![PriceLevel {
+                price: decimal!("115.0"),
+                quantity: decimal!("3.5"),
+            }
+            .to_proto(&exchange2)]
+        );
+    }
+
+    #[test]
+    fn test_convert_quote() {
+        let binance = "binance".to_string();
+        let bitstamp = "bitstamp".to_string();
+
+        let fx_rates = FxRates::default();
+        let mut merged_summary = OrderBookMerger::default();
+        merged_summary.set_quote_conversion("usdt", fx_rates.clone());
+        merged_summary.set_exchange_quote(&binance, "USDT");
+        merged_summary.set_exchange_quote(&bitstamp, "usd");
+
+        merged_summary.insert(
+            &binance,
+            create_order_book(
+                vec![(decimal!("100.0"), decimal!("1.0"))],
+                vec![(decimal!("101.0"), decimal!("1.0"))],
+            ),
+        );
+        merged_summary.insert(
+            &bitstamp,
+            create_order_book(
+                vec![(decimal!("50.0"), decimal!("2.0"))],
+                vec![(decimal!("50.4"), decimal!("2.0"))],
+            ),
+        );
+
+        // There is no rate of usd yet, so only the book in usdt is merged
+        let summary = merged_summary.get_summary();
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.bids[0].exchange, binance);
+
+        fx_rates.set(Pair::new("usdt", "usd"), decimal!("0.5"));
+
+        let summary = merged_summary.get_summary();
+        assert_eq!(
+            summary.bids,
+            vec![
+                PriceLevel {
+                    price: decimal!("50.0"),
+                    quantity: decimal!("2.0"),
+                }
+                .to_converted_proto(&bitstamp, "usd", decimal!("2"))
+                .unwrap(),
+                PriceLevel {
+                    price: decimal!("100.0"),
+                    quantity: decimal!("1.0"),
+                }
+                .to_proto(&binance),
+            ]
+        );
+        assert_eq!(summary.bids[0].quote_currency, "usd");
+        assert_eq!(
+            Decimal::from(summary.bids[0].price.as_ref().unwrap()),
+            decimal!("100.0")
+        );
+        assert_eq!(
+            Decimal::from(summary.asks[0].price.as_ref().unwrap()),
+            decimal!("100.8")
+        );
+        assert_eq!(summary.asks[0].exchange, bitstamp);
+
+        // Prices overflowing by the rate can't be merged either
+        fx_rates.set(
+            Pair::new("usdt", "usd"),
+            decimal!("0.0000000000000000000000000001"),
+        );
+        let summary = merged_summary.get_summary();
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.bids[0].exchange, binance);
+    }
+
+    #[test]
+    fn test_taker_fees() {
+        let binance = "binance".to_string();
+        let kraken = "kraken".to_string();
+
+        let mut merged_summary = OrderBookMerger::default();
+        merged_summary.set_taker_fees(Some(HashMap::from([(kraken.clone(), decimal!("0.01"))])));
+
+        merged_summary.insert(
+            &binance,
+            create_order_book(
+                vec![(decimal!("99.5"), decimal!("1.0"))],
+                vec![(decimal!("100.5"), decimal!("1.0"))],
+            ),
+        );
+        merged_summary.insert(
+            &kraken,
+            create_order_book(
+                vec![(decimal!("100.0"), decimal!("2.0"))],
+                vec![(decimal!("100.0"), decimal!("2.0"))],
+            ),
+        );
+
+        // Kraken is the best by raw prices, but not after its fee
+        let summary = merged_summary.get_summary();
+        let prices = |levels: &[crate::proto::PriceLevel]| {
+            levels
+                .iter()
+                .map(|l| {
+                    (
+                        l.exchange.clone(),
+                        Decimal::from(l.price.as_ref().unwrap()),
+                        Decimal::from(l.raw_price.as_ref().unwrap()),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            prices(&summary.asks),
+            vec![
+                (binance.clone(), decimal!("100.5"), decimal!("100.5")),
+                (kraken.clone(), decimal!("101"), decimal!("100")),
+            ]
+        );
+        assert_eq!(
+            prices(&summary.bids),
+            vec![
+                (binance.clone(), decimal!("99.5"), decimal!("99.5")),
+                (kraken.clone(), decimal!("99"), decimal!("100")),
+            ]
+        );
+
+        merged_summary.set_taker_fees(None);
+        let summary = merged_summary.get_summary();
+        assert_eq!(summary.asks[0].exchange, kraken);
+        assert_eq!(summary.asks[0].raw_price, None);
+    }
+
+    #[test]
+    fn test_consolidated() {
+        let exchange1 = "exchange1".to_string();
+        let exchange2 = "exchange2".to_string();
+
+        let mut merged_summary = OrderBookMerger::new(2);
+        merged_summary.set_consolidated(true);
+
+        merged_summary.insert(
+            &exchange1,
+            create_order_book(
+                vec![
+                    (decimal!("100.0"), decimal!("1.0")),
+                    (decimal!("99.0"), decimal!("2.0")),
+                ],
+                vec![
+                    (decimal!("101.0"), decimal!("3.0")),
+                    (decimal!("102.0"), decimal!("4.0")),
+                ],
+            ),
+        );
+        merged_summary.insert(
+            &exchange2,
+            create_order_book(
+                vec![
+                    (decimal!("100.00"), decimal!("1.5")),
+                    (decimal!("98.0"), decimal!("2.5")),
+                ],
+                vec![(decimal!("101.5"), decimal!("3.5"))],
+            ),
+        );
+
+        let level = |price: &str, quantity: &str, exchange: &str| {
+            PriceLevel {
+                price: Decimal::from_str(price).unwrap(),
+                quantity: Decimal::from_str(quantity).unwrap(),
+            }
+            .to_proto(exchange)
+        };
+
+        let summary = merged_summary.get_summary();
+        assert_eq!(summary.bids.len(), 2);
+        assert_eq!(summary.bids[0].exchange, "");
+        assert_eq!(
+            Decimal::from(summary.bids[0].amount.as_ref().unwrap()),
+            decimal!("2.5")
+        );
+        assert_eq!(
+            summary.bids[0].breakdown,
+            vec![
+                level("100.00", "1.5", &exchange2),
+                level("100.0", "1.0", &exchange1),
+            ]
+        );
+        assert_eq!(
+            summary.bids[1].breakdown,
+            vec![level("99.0", "2.0", &exchange1)]
+        );
+        assert_eq!(
+            summary.asks[0].breakdown,
+            vec![level("101.0", "3.0", &exchange1)]
+        );
+        assert_eq!(
+            summary.asks[1].breakdown,
+            vec![level("101.5", "3.5", &exchange2)]
+        );
+
+        // Consolidated levels are narrowed to exchanges of a subscriber, cut after filtering
+        let filtered = merged_summary
+            .get_exchanges_summary()
+            .filtered(2, &[exchange1.clone()]);
+        assert_eq!(
+            Decimal::from(filtered.bids[0].amount.as_ref().unwrap()),
+            decimal!("1.0")
+        );
+        assert_eq!(
+            filtered.bids[0].breakdown,
+            vec![level("100.0", "1.0", &exchange1)]
+        );
+        assert_eq!(filtered.asks.len(), 2);
+    }
 }